@@ -0,0 +1,117 @@
+// Compares the SIMD kernels against their scalar fallbacks on the test fixtures.
+//
+// Run with `cargo bench --bench simd --features bench-internals`.
+
+use std::time::{Duration, Instant};
+
+use bsdiff_android::simd;
+
+/// Runs `f` repeatedly for about half a second and returns the best time per run
+fn time<F: FnMut() -> usize>(mut f: F) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut checksum = 0usize;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        let t = Instant::now();
+        checksum = checksum.wrapping_add(f());
+        best = best.min(t.elapsed());
+    }
+    (best, checksum)
+}
+
+fn report<F: FnMut() -> usize, G: FnMut() -> usize>(name: &str, bytes: usize, fast: F, scalar: G) {
+    let (fast, a) = time(fast);
+    let (scalar, b) = time(scalar);
+    let gib_s = |d: Duration| bytes as f64 / d.as_secs_f64() / (1u64 << 30) as f64;
+    println!(
+        "{:<24} simd {:>7.2} GiB/s   scalar {:>7.2} GiB/s   speedup {:.2}x   ({:x})",
+        name,
+        gib_s(fast),
+        gib_s(scalar),
+        scalar.as_secs_f64() / fast.as_secs_f64(),
+        a ^ b,
+    );
+}
+
+fn main() {
+    let old = std::fs::read("tests/test_1").unwrap();
+    let new = std::fs::read("tests/test_2").unwrap();
+    let len = old.len().min(new.len());
+
+    let (mut a, mut b) = (new[..len].to_vec(), new[..len].to_vec());
+    report(
+        "add_bytes",
+        len,
+        || {
+            simd::add_bytes(&mut a, &old);
+            a[len / 2] as usize
+        },
+        || {
+            simd::add_bytes_scalar(&mut b, &old);
+            b[len / 2] as usize
+        },
+    );
+
+    // The loop patch_bsdf2 used before, with a bounds-checked read of old per byte
+    report(
+        "add vs per-byte get",
+        len,
+        || {
+            simd::add_bytes(&mut a, &old);
+            a[len / 2] as usize
+        },
+        || {
+            for (i, d) in b.iter_mut().enumerate() {
+                *d = old.get(i).copied().unwrap_or(0).wrapping_add(*d);
+            }
+            b[len / 2] as usize
+        },
+    );
+
+    // A long match: the old file against a copy of itself
+    let copy = old.clone();
+    report(
+        "matchlen (identical)",
+        old.len(),
+        || simd::matchlen(&old, &copy),
+        || simd::matchlen_scalar(&old, &copy),
+    );
+
+    // Many short and medium matches, like the tail of a suffix array search
+    let starts: Vec<usize> = (0..len).step_by(4096).collect();
+    let scan = |f: fn(&[u8], &[u8]) -> usize| {
+        starts
+            .iter()
+            .map(|&i| f(&old[i..], &new[i..]))
+            .sum::<usize>()
+    };
+    let matched = scan(simd::matchlen_scalar);
+    report(
+        "matchlen (fixtures)",
+        matched + starts.len(),
+        || scan(simd::matchlen),
+        || scan(simd::matchlen_scalar),
+    );
+
+    // End to end, for context
+    let (d, patch_len) = time(|| {
+        let mut patch = Vec::new();
+        bsdiff_android::diff_bsdiff40(&old, &new, &mut patch).unwrap();
+        patch.len()
+    });
+    println!("{:<24} {:>7.1} ms", "diff_bsdiff40", d.as_secs_f64() * 1e3);
+
+    let mut patch = Vec::new();
+    bsdiff_android::diff_bsdiff40(&old, &new, &mut patch).unwrap();
+    let (d, _) = time(|| {
+        let mut out = Vec::new();
+        bsdiff_android::patch_bsdf2(&old, &patch, &mut out).unwrap();
+        out.len()
+    });
+    println!(
+        "{:<24} {:>7.1} ms   ({} byte patch)",
+        "patch_bsdf2",
+        d.as_secs_f64() * 1e3,
+        patch_len
+    );
+}
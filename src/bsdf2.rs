@@ -1,39 +1,24 @@
 // bsdf2.rs - Android BSDF2 format
 
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 
-const BSDIFF_MAGIC: &[u8; 8] = b"BSDIFF40";
-const BSDF2_MAGIC: &[u8; 5] = b"BSDF2";
+use crate::bsdf2_writer::{encode_int64, CompressionAlgorithm};
+use crate::control::TupleValidator;
+use crate::error::{Limit, PatchError, Position, Stream};
+use crate::format::PatchFormat;
+use crate::patch::{try_reserve, PatchLimits, PatchOptions};
+use crate::simd;
 
-// Safety limits to prevent OOM
-// const MAX_PATCH_SIZE: usize = 512 * 1024 * 1024; // 512 MB
-const MAX_NEW_SIZE: usize = 2 * 1024 * 1024 * 1024; // 2 GB
+pub(crate) const BSDIFF_MAGIC: &[u8; 8] = b"BSDIFF40";
+pub(crate) const BSDF2_MAGIC: &[u8; 5] = b"BSDF2";
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum CompressionAlgorithm {
-    None = 0,
-    Bz2 = 1,
-    Brotli = 2,
-}
-
-impl CompressionAlgorithm {
-    fn from_u8(value: u8) -> io::Result<Self> {
-        match value {
-            0 => Ok(Self::None),
-            1 => Ok(Self::Bz2),
-            2 => Ok(Self::Brotli),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Unknown compression algorithm: {}", value),
-            )),
-        }
-    }
-}
+/// Granularity of the fallible buffer growth while decompressing
+const DECOMPRESS_CHUNK: usize = 64 * 1024;
 
 /// Reads sign-magnitude i64 as used in bspatch
 /// This is NOT plain little-endian - it uses sign-magnitude encoding
 #[inline]
-fn offtin(buf: [u8; 8]) -> i64 {
+pub(crate) fn offtin(buf: [u8; 8]) -> i64 {
     let y = i64::from_le_bytes(buf);
     if 0 == y & (1 << 63) {
         y
@@ -42,278 +27,340 @@ fn offtin(buf: [u8; 8]) -> i64 {
     }
 }
 
-/// Decompress data based on algorithm
-fn decompress(alg: CompressionAlgorithm, data: &[u8]) -> io::Result<Vec<u8>> {
+/// Decompress data based on algorithm, stopping after `cap` bytes
+pub(crate) fn decompress(alg: CompressionAlgorithm, data: &[u8], cap: u64) -> io::Result<Vec<u8>> {
     match alg {
-        CompressionAlgorithm::None => Ok(data.to_vec()),
-        CompressionAlgorithm::Bz2 => {
-            let mut decompressed = Vec::new();
-            let mut decoder = bzip2::read::BzDecoder::new(data);
-            decoder.read_to_end(&mut decompressed)?;
-            Ok(decompressed)
-        }
-        CompressionAlgorithm::Brotli => {
-            let mut decompressed = Vec::new();
-            let mut decoder = brotli::Decompressor::new(data, 4096);
-            decoder.read_to_end(&mut decompressed)?;
-            Ok(decompressed)
-        }
+        CompressionAlgorithm::None => read_capped(data, cap),
+        CompressionAlgorithm::Bz2 => read_capped(bzip2::read::BzDecoder::new(data), cap),
+        CompressionAlgorithm::Brotli => read_capped(brotli::Decompressor::new(data, 4096), cap),
     }
 }
 
-/// Parse BSDF2 or classic BSDIFF patch header and return streams
-pub fn parse_bsdf2_header(
-    patch_data: &[u8],
-) -> io::Result<(i64, Vec<u8>, Vec<u8>, Vec<u8>)> {
-    if patch_data.len() < 32 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Patch data too short",
-        ));
+/// Like `read_to_end`, but bounded by `cap` and without aborting on allocation failure
+fn read_capped<R: Read>(reader: R, cap: u64) -> io::Result<Vec<u8>> {
+    let mut reader = reader.take(cap);
+    let mut out = Vec::new();
+    loop {
+        let len = out.len();
+        try_reserve(&mut out, DECOMPRESS_CHUNK)?;
+        out.resize(len + DECOMPRESS_CHUNK, 0);
+        match reader.read(&mut out[len..]) {
+            Ok(0) => {
+                out.truncate(len);
+                break;
+            }
+            Ok(n) => out.truncate(len + n),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => out.truncate(len),
+            Err(e) => return Err(e),
+        }
     }
+    Ok(out)
+}
 
-    let magic = &patch_data[0..8];
+/// The fixed 32-byte header of a BSDIFF40 or BSDF2 patch.
+///
+/// It is followed by the compressed control stream (`ctrl_len` bytes), the
+/// compressed diff stream (`diff_len` bytes) and the compressed extra stream,
+/// which runs to the end of the patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bsdf2Header {
+    /// `PatchFormat::Bsdiff40` or `PatchFormat::Bsdf2`
+    pub format: PatchFormat,
+    pub ctrl_alg: CompressionAlgorithm,
+    pub diff_alg: CompressionAlgorithm,
+    pub extra_alg: CompressionAlgorithm,
+    /// Compressed size of the control stream
+    pub ctrl_len: u64,
+    /// Compressed size of the diff stream
+    pub diff_len: u64,
+    /// Size of the new file
+    pub new_size: u64,
+}
 
-    // Determine format and compression algorithms
-    let (alg_control, alg_diff, alg_extra) = if magic == BSDIFF_MAGIC {
-        // Classic BSDIFF format - uses BZ2 for all streams
-        (
-            CompressionAlgorithm::Bz2,
-            CompressionAlgorithm::Bz2,
-            CompressionAlgorithm::Bz2,
-        )
-    } else if &magic[0..5] == BSDF2_MAGIC {
-        // BSDF2 format - per-stream compression
-        (
-            CompressionAlgorithm::from_u8(magic[5])?,
-            CompressionAlgorithm::from_u8(magic[6])?,
-            CompressionAlgorithm::from_u8(magic[7])?,
-        )
-    } else {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Invalid BSDIFF/BSDF2 magic header",
-        ));
-    };
+impl Bsdf2Header {
+    /// Size of the encoded header in bytes
+    pub const SIZE: usize = 32;
+
+    /// Parse the header from the first 32 bytes of a patch, without touching the streams
+    pub fn parse(patch_data: &[u8]) -> io::Result<Self> {
+        let header = patch_data.get(..Self::SIZE).ok_or(PatchError::Truncated {
+            at: Position::default(),
+        })?;
+        let magic = &header[0..8];
+
+        // Determine format and compression algorithms
+        let (format, ctrl_alg, diff_alg, extra_alg) = if magic == BSDIFF_MAGIC {
+            // Classic BSDIFF format - uses BZ2 for all streams
+            (
+                PatchFormat::Bsdiff40,
+                CompressionAlgorithm::Bz2,
+                CompressionAlgorithm::Bz2,
+                CompressionAlgorithm::Bz2,
+            )
+        } else if &magic[0..5] == BSDF2_MAGIC {
+            // BSDF2 format - per-stream compression
+            (
+                PatchFormat::Bsdf2,
+                CompressionAlgorithm::from_u8(magic[5], Stream::Control)?,
+                CompressionAlgorithm::from_u8(magic[6], Stream::Diff)?,
+                CompressionAlgorithm::from_u8(magic[7], Stream::Extra)?,
+            )
+        } else {
+            return Err(PatchError::BadMagic.into());
+        };
+
+        // Read length headers using bspatch integer encoding
+        let ctrl_len = offtin(header[8..16].try_into().unwrap());
+        let diff_len = offtin(header[16..24].try_into().unwrap());
+        let new_size = offtin(header[24..32].try_into().unwrap());
+
+        if let Some(&value) = [ctrl_len, diff_len, new_size].iter().find(|&&l| l < 0) {
+            return Err(PatchError::NegativeLength {
+                at: Position::default(),
+                value,
+            }
+            .into());
+        }
+
+        Ok(Self {
+            format,
+            ctrl_alg,
+            diff_alg,
+            extra_alg,
+            ctrl_len: ctrl_len as u64,
+            diff_len: diff_len as u64,
+            new_size: new_size as u64,
+        })
+    }
 
-    // Read length headers using bspatch integer encoding
-    let len_control = offtin(patch_data[8..16].try_into().unwrap());
-    let len_diff = offtin(patch_data[16..24].try_into().unwrap());
-    let new_size = offtin(patch_data[24..32].try_into().unwrap());
+    /// Encode the header.
+    ///
+    /// A `Bsdiff40` header requires all three streams to be bzip2-compressed, as its
+    /// magic has no room for codec bytes.
+    pub fn to_bytes(&self) -> io::Result<[u8; 32]> {
+        let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, msg.to_string()));
+        let mut header = [0u8; 32];
+
+        match self.format {
+            PatchFormat::Bsdiff40 => {
+                if [self.ctrl_alg, self.diff_alg, self.extra_alg]
+                    .iter()
+                    .any(|&alg| alg != CompressionAlgorithm::Bz2)
+                {
+                    return invalid("BSDIFF40 patches must use bzip2 for every stream");
+                }
+                header[0..8].copy_from_slice(BSDIFF_MAGIC);
+            }
+            PatchFormat::Bsdf2 => {
+                header[0..5].copy_from_slice(BSDF2_MAGIC);
+                header[5] = self.ctrl_alg as u8;
+                header[6] = self.diff_alg as u8;
+                header[7] = self.extra_alg as u8;
+            }
+            _ => return invalid("Header format must be Bsdiff40 or Bsdf2"),
+        }
 
-    // Validate lengths before allocation
-    if len_control < 0 || len_diff < 0 || new_size < 0 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Negative length in patch header",
-        ));
+        let lengths = [self.ctrl_len, self.diff_len, self.new_size];
+        for (field, &len) in header[8..].chunks_exact_mut(8).zip(&lengths) {
+            match i64::try_from(len) {
+                Ok(len) => encode_int64(len, field),
+                Err(_) => return invalid("Header length does not fit in 63 bits"),
+            }
+        }
+        Ok(header)
     }
 
-    let len_control = len_control as usize;
-    let len_diff = len_diff as usize;
-    let new_size_usize = new_size as usize;
+    /// Encode the header into `writer`
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes()?)
+    }
+}
+
+/// Parse and validate the fixed 32-byte header
+pub(crate) fn read_header(header: &[u8; 32], limits: &PatchLimits) -> io::Result<Bsdf2Header> {
+    let header = Bsdf2Header::parse(header)?;
 
     // Safety checks before allocation
-    if new_size_usize > MAX_NEW_SIZE {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("New size {} exceeds limit", new_size_usize),
-        ));
+    PatchLimits::check(
+        Position::default(),
+        Limit::NewSize,
+        limits.max_new_size,
+        header.new_size,
+    )?;
+    Ok(header)
+}
+
+/// Parse a BSDF2 or classic BSDIFF patch and decompress its three streams.
+///
+/// Returns the new size and the decompressed control, diff and extra streams. Use
+/// `Bsdf2Header::parse` to read only the metadata.
+#[allow(clippy::type_complexity)]
+pub fn parse_bsdf2_header(patch_data: &[u8]) -> io::Result<(i64, Vec<u8>, Vec<u8>, Vec<u8>)> {
+    parse_with_limits(patch_data, &PatchLimits::default())
+}
+
+/// `parse_bsdf2_header` with caller-chosen limits on the decompressed streams
+#[allow(clippy::type_complexity)]
+pub(crate) fn parse_with_limits(
+    patch_data: &[u8],
+    limits: &PatchLimits,
+) -> io::Result<(i64, Vec<u8>, Vec<u8>, Vec<u8>)> {
+    if patch_data.len() < 32 {
+        return Err(PatchError::Truncated {
+            at: Position::default(),
+        }
+        .into());
     }
 
-    
-    
-    let pos: usize = 32;
+    let header = read_header(patch_data[..32].try_into().unwrap(), limits)?;
+    let new_size = header.new_size as i64;
+    let overrun = || PatchError::Truncated {
+        at: Position::default(),
+    };
+    let len_control = usize::try_from(header.ctrl_len).map_err(|_| overrun())?;
+    let len_diff = usize::try_from(header.diff_len).map_err(|_| overrun())?;
+
+    // Decompress one stream. Diff and extra data beyond the new size can never be
+    // consumed, so their decompression stops there even below the configured limit.
+    let max = limits.max_stream_size;
+    let read_stream = |alg, data: &[u8], stream, consumable: u64| {
+        let out = decompress(alg, data, max.min(consumable).saturating_add(1))?;
+        let at = Position::default();
+        PatchLimits::check(at, Limit::StreamSize(stream), max, out.len() as u64)?;
+        if out.len() as u64 > consumable {
+            return Err(PatchError::UnconsumedData { at, stream }.into());
+        }
+        Ok::<_, io::Error>(out)
+    };
 
+    let pos: usize = 32;
 
     // Validate lengths don't exceed patch bounds
-    if pos.checked_add(len_control)
+    if pos
+        .checked_add(len_control)
         .and_then(|p| p.checked_add(len_diff))
         .map_or(true, |total| total > patch_data.len())
     {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Stream lengths exceed patch size",
-        ));
+        return Err(overrun().into());
     }
 
     // Read and decompress control stream
     let control_end = pos + len_control;
-    if control_end > patch_data.len() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Control stream exceeds patch bounds",
-        ));
-    }
     let control_compressed = &patch_data[pos..control_end];
-    let control_data = decompress(alg_control, control_compressed)?;
+    let control_data = read_stream(
+        header.ctrl_alg,
+        control_compressed,
+        Stream::Control,
+        u64::MAX,
+    )?;
+    PatchLimits::check(
+        Position::default(),
+        Limit::ControlTuples,
+        limits.max_control_tuples,
+        (control_data.len() / 24) as u64,
+    )?;
 
     // Validate control data is properly aligned (24 bytes per tuple)
     if control_data.len() % 24 != 0 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Invalid control data length (not multiple of 24)",
-        ));
+        return Err(PatchError::StreamExhausted {
+            at: Position {
+                tuple: (control_data.len() / 24) as u64,
+                ..Position::default()
+            },
+            stream: Stream::Control,
+        }
+        .into());
     }
 
     // Read and decompress diff stream
     let diff_start = control_end;
     let diff_end = diff_start + len_diff;
-    if diff_end > patch_data.len() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Diff stream exceeds patch bounds",
-        ));
-    }
     let diff_compressed = &patch_data[diff_start..diff_end];
-    let diff_data = decompress(alg_diff, diff_compressed)?;
+    let diff_data = read_stream(
+        header.diff_alg,
+        diff_compressed,
+        Stream::Diff,
+        new_size as u64,
+    )?;
 
     // Read and decompress extra stream (rest of data)
     let extra_compressed = &patch_data[diff_end..];
-    let extra_data = decompress(alg_extra, extra_compressed)?;
+    let extra_data = read_stream(
+        header.extra_alg,
+        extra_compressed,
+        Stream::Extra,
+        new_size as u64,
+    )?;
 
     Ok((new_size, control_data, diff_data, extra_data))
 }
 
 /// Apply a BSDF2 patch with full validation and optimizations
 pub fn patch_bsdf2(old: &[u8], patch_data: &[u8], new: &mut Vec<u8>) -> io::Result<()> {
+    patch_bsdf2_with_options(old, patch_data, new, &PatchOptions::default())
+}
+
+/// Apply a BSDF2 patch with progress reporting and cancellation
+pub fn patch_bsdf2_with_options(
+    old: &[u8],
+    patch_data: &[u8],
+    new: &mut Vec<u8>,
+    options: &PatchOptions,
+) -> io::Result<()> {
     // Parse header and decompress streams
-    let (new_size, control_data, diff_data, extra_data) = parse_bsdf2_header(patch_data)?;
+    let (new_size, control_data, diff_data, extra_data) =
+        parse_with_limits(patch_data, &options.limits)?;
 
     let new_size = new_size as usize;
+    let mut reporter = options.reporter(new_size as u64);
 
     // Pre-allocate output buffer
     new.clear();
-    new.reserve(new_size);
+    try_reserve(new, new_size)?;
 
-    let mut oldpos: usize = 0;
-    let mut diff_pos: usize = 0;
-    let mut extra_pos: usize = 0;
+    let mut tuples = TupleValidator::new(Some(new_size as u64))
+        .with_streams(diff_data.len() as u64, extra_data.len() as u64)
+        .with_old_bounds(old.len() as u64, options.old_bounds);
 
     // Process control tuples
-    let mut ctrl_idx = 0;
-    while ctrl_idx < control_data.len() {
-        if ctrl_idx + 24 > control_data.len() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Incomplete control tuple",
-            ));
-        }
-
-        // Read control tuple using bspatch integer encoding
-        let add_len = offtin(control_data[ctrl_idx..ctrl_idx + 8].try_into().unwrap());
-        let copy_len = offtin(control_data[ctrl_idx + 8..ctrl_idx + 16].try_into().unwrap());
-        let seek_amount = offtin(control_data[ctrl_idx + 16..ctrl_idx + 24].try_into().unwrap());
-        ctrl_idx += 24;
-
-        // Validate lengths
-        if add_len < 0 || copy_len < 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Negative length in control tuple: add={}, copy={}", add_len, copy_len),
-            ));
-        }
-
-        let add_len = add_len as usize;
-        let copy_len = copy_len as usize;
-
-        // Check we won't exceed output size
-        if new.len().checked_add(add_len)
-            .and_then(|n| n.checked_add(copy_len))
-            .map_or(true, |total| total > new_size)
-        {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Control tuple would exceed new_size",
-            ));
-        }
+    let mut control = control_data.chunks_exact(24);
+    for ctrl in &mut control {
+        reporter.update(new.len() as u64)?;
+        let tuple = tuples.next(ctrl.try_into().unwrap())?;
+        let (add_len, copy_len) = (tuple.add_len as usize, tuple.copy_len as usize);
 
         // ADD operation: new[newpos..newpos+add] = old[oldpos..] + diff[diff_pos..]
-        if add_len > 0 {
-            // Check diff_data bounds
-            if diff_pos.checked_add(add_len).map_or(true, |end| end > diff_data.len()) {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Diff data exhausted",
-                ));
-            }
-
-            // Optimized: reserve space and write directly
-            let new_start = new.len();
-            new.resize(new_start + add_len, 0);
-
-            // SIMD-friendly loop: compute in chunks
-            for i in 0..add_len {
-                let old_byte = old.get(oldpos + i).copied().unwrap_or(0);
-                let diff_byte = diff_data[diff_pos + i];
-                new[new_start + i] = old_byte.wrapping_add(diff_byte);
-            }
-
-            oldpos = oldpos.saturating_add(add_len);
-            diff_pos += add_len;
-        }
+        // Start from the diff bytes and add old on top; bytes past the end of old are
+        // added to zero
+        let diff_pos = tuple.diff_pos as usize;
+        let new_start = new.len();
+        new.extend_from_slice(&diff_data[diff_pos..diff_pos + add_len]);
+        let oldpos = usize::try_from(tuple.at.old_pos).unwrap_or(usize::MAX);
+        simd::add_bytes(&mut new[new_start..], old.get(oldpos..).unwrap_or(&[]));
 
         // COPY operation: new[newpos..newpos+copy] = extra[extra_pos..]
-        if copy_len > 0 {
-            // Check extra_data bounds
-            if extra_pos.checked_add(copy_len).map_or(true, |end| end > extra_data.len()) {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Extra data exhausted",
-                ));
-            }
-
-            new.extend_from_slice(&extra_data[extra_pos..extra_pos + copy_len]);
-            extra_pos += copy_len;
-        }
-
-        // SEEK operation: adjust oldpos
-        // CRITICAL: Validate seek doesn't underflow
-        let new_oldpos = (oldpos as i64)
-            .checked_add(seek_amount)
-            .ok_or_else(|| {
-                io::Error::new(io::ErrorKind::InvalidData, "Seek overflow")
-            })?;
-
-        if new_oldpos < 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Seek underflow: oldpos={}, seek={}", oldpos, seek_amount),
-            ));
-        }
-
-        oldpos = new_oldpos as usize;
+        let extra_pos = tuple.extra_pos as usize;
+        new.extend_from_slice(&extra_data[extra_pos..extra_pos + copy_len]);
     }
-
-    // Validate final state
-    if new.len() != new_size {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("Final size mismatch: expected {}, got {}", new_size, new.len()),
-        ));
-    }
-
-    // Validate all streams were fully consumed
-    if diff_pos != diff_data.len() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("Diff data not fully consumed: used {}/{}", diff_pos, diff_data.len()),
-        ));
+    if !control.remainder().is_empty() {
+        return Err(PatchError::StreamExhausted {
+            at: tuples.at(),
+            stream: Stream::Control,
+        }
+        .into());
     }
 
-    if extra_pos != extra_data.len() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("Extra data not fully consumed: used {}/{}", extra_pos, extra_data.len()),
-        ));
-    }
+    // Validate final state and that all streams were fully consumed
+    tuples.finish()?;
 
+    reporter.report(new.len() as u64);
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::patch::OldBoundsMode;
 
     #[test]
     fn test_offtin_positive() {
@@ -328,12 +375,89 @@ mod tests {
         assert_eq!(offtin(buf), -0x42);
     }
 
+    #[test]
+    fn test_header_roundtrip() {
+        let mut patch = Vec::new();
+        crate::diff_bsdf2(
+            b"old data",
+            b"new data!",
+            &mut patch,
+            CompressionAlgorithm::Brotli,
+            CompressionAlgorithm::None,
+            CompressionAlgorithm::Bz2,
+        )
+        .unwrap();
+
+        let header = Bsdf2Header::parse(&patch).unwrap();
+        assert_eq!(header.format, PatchFormat::Bsdf2);
+        assert_eq!(
+            (header.ctrl_alg, header.diff_alg, header.extra_alg),
+            (
+                CompressionAlgorithm::Brotli,
+                CompressionAlgorithm::None,
+                CompressionAlgorithm::Bz2
+            )
+        );
+        assert_eq!(header.new_size, 9);
+        assert_eq!(&header.to_bytes().unwrap()[..], &patch[..32]);
+
+        let legacy = Bsdf2Header {
+            format: PatchFormat::Bsdiff40,
+            ..header
+        };
+        assert!(legacy.to_bytes().is_err());
+    }
+
     #[test]
     fn test_compression_algorithm_from_u8() {
-        assert_eq!(CompressionAlgorithm::from_u8(0).unwrap(), CompressionAlgorithm::None);
-        assert_eq!(CompressionAlgorithm::from_u8(1).unwrap(), CompressionAlgorithm::Bz2);
-        assert_eq!(CompressionAlgorithm::from_u8(2).unwrap(), CompressionAlgorithm::Brotli);
-        assert!(CompressionAlgorithm::from_u8(3).is_err());
+        let from_u8 = |v| CompressionAlgorithm::from_u8(v, Stream::Diff);
+        assert_eq!(from_u8(0).unwrap(), CompressionAlgorithm::None);
+        assert_eq!(from_u8(1).unwrap(), CompressionAlgorithm::Bz2);
+        assert_eq!(from_u8(2).unwrap(), CompressionAlgorithm::Brotli);
+        assert_eq!(
+            from_u8(3),
+            Err(PatchError::UnknownCodec {
+                stream: Stream::Diff,
+                value: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_patch_progress_and_cancel() {
+        use crate::progress::{is_cancelled, CancelToken, ProgressCallback};
+        use std::sync::{Arc, Mutex};
+
+        let old: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[10_000..10_100].fill(0);
+        let mut patch = Vec::new();
+        crate::diff_bsdiff40(&old, &new, &mut patch).unwrap();
+
+        let last = Arc::new(Mutex::new(None));
+        let sink = Arc::clone(&last);
+        let options = PatchOptions {
+            progress: Some(ProgressCallback::new(move |p| {
+                *sink.lock().unwrap() = Some(p)
+            })),
+            ..PatchOptions::default()
+        };
+        let mut out = Vec::new();
+        patch_bsdf2_with_options(&old, &patch, &mut out, &options).unwrap();
+        let last = last.lock().unwrap().unwrap();
+        assert_eq!(
+            (last.done, last.total),
+            (new.len() as u64, new.len() as u64)
+        );
+
+        let token = CancelToken::new();
+        token.cancel();
+        let options = PatchOptions {
+            cancel: Some(token),
+            ..PatchOptions::default()
+        };
+        let err = patch_bsdf2_with_options(&old, &patch, &mut out, &options).unwrap_err();
+        assert!(is_cancelled(&err));
     }
 
     #[test]
@@ -349,7 +473,143 @@ mod tests {
         // Set negative length (sign bit set)
         data[8] = 0x01;
         data[15] = 0x80; // Sign bit
-        
+
         assert!(parse_bsdf2_header(&data).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_typed_errors() {
+        use crate::bsdf2_writer::{Bsdf2Writer, CompressionAlgorithm as Alg, ControlEntry};
+        use crate::error::patch_error;
+
+        let old = vec![1u8; 100];
+        let write = |entries: &[(i64, i64, i64)], diff_len: usize| {
+            let mut writer = Bsdf2Writer::new(Alg::None, Alg::None, Alg::None);
+            for &(diff_size, extra_size, offset_increment) in entries {
+                writer
+                    .add_control_entry(ControlEntry {
+                        diff_size,
+                        extra_size,
+                        offset_increment,
+                    })
+                    .unwrap();
+            }
+            writer.write_diff_stream(&vec![0u8; diff_len]).unwrap();
+            let mut patch = Vec::new();
+            writer.close(&mut patch).unwrap();
+            patch
+        };
+
+        // Second tuple seeks before the start of old
+        let patch = write(&[(10, 0, 0), (10, 0, -30)], 20);
+        let err = patch_bsdf2(&old, &patch, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            patch_error(&err),
+            Some(&PatchError::SeekUnderflow {
+                at: Position {
+                    tuple: 1,
+                    old_pos: 10,
+                    new_pos: 10
+                },
+                seek: -30,
+            })
+        );
+
+        let patch = write(&[(10, 0, 0), (10, 0, 0)], 15);
+        let err = patch_bsdf2(&old, &patch, &mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            patch_error(&err),
+            Some(PatchError::StreamExhausted { stream: Stream::Diff, at }) if at.tuple == 1
+        ));
+
+        let err = patch_bsdf2(&old, &patch[..20], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(matches!(
+            patch_error(&err),
+            Some(PatchError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn test_patch_limits() {
+        use crate::error::patch_error;
+
+        let old = vec![0u8; 1000];
+        let new = vec![0u8; 200_000];
+        let mut patch = Vec::new();
+        crate::diff_bsdiff40(&old, &new, &mut patch).unwrap();
+
+        let exceeded = |limits: PatchLimits| {
+            let options = PatchOptions {
+                limits,
+                ..PatchOptions::default()
+            };
+            let err =
+                patch_bsdf2_with_options(&old, &patch, &mut Vec::new(), &options).unwrap_err();
+            match patch_error(&err) {
+                Some(PatchError::LimitExceeded { limit, .. }) => *limit,
+                other => panic!("unexpected error {:?}", other),
+            }
+        };
+
+        let defaults = PatchLimits::default();
+        let new_size = PatchLimits {
+            max_new_size: 100_000,
+            ..defaults
+        };
+        assert_eq!(exceeded(new_size), Limit::NewSize);
+        let stream = PatchLimits {
+            max_stream_size: 1000,
+            ..defaults
+        };
+        assert_eq!(exceeded(stream), Limit::StreamSize(Stream::Extra));
+        let tuples = PatchLimits {
+            max_control_tuples: 0,
+            ..defaults
+        };
+        assert_eq!(exceeded(tuples), Limit::ControlTuples);
+
+        let mut out = Vec::new();
+        patch_bsdf2(&old, &patch, &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn test_seek_past_end_of_old() {
+        use crate::bsdf2_writer::{Bsdf2Writer, CompressionAlgorithm as Alg, ControlEntry};
+
+        let old = [10u8, 20, 30, 40];
+        let mut writer = Bsdf2Writer::new(Alg::None, Alg::Bz2, Alg::None);
+        writer
+            .add_control_entry(ControlEntry {
+                diff_size: 2,
+                extra_size: 0,
+                offset_increment: 100,
+            })
+            .unwrap();
+        writer
+            .add_control_entry(ControlEntry {
+                diff_size: 4,
+                extra_size: 0,
+                offset_increment: 0,
+            })
+            .unwrap();
+        writer.write_diff_stream(&[1, 1, 1, 2, 3, 4]).unwrap();
+        let mut patch = Vec::new();
+        writer.close(&mut patch).unwrap();
+
+        let mut out = Vec::new();
+        patch_bsdf2(&old, &patch, &mut out).unwrap();
+        assert_eq!(out, [11, 21, 1, 2, 3, 4]);
+
+        let options = PatchOptions {
+            old_bounds: OldBoundsMode::Strict,
+            ..PatchOptions::default()
+        };
+        let err = patch_bsdf2_with_options(&old, &patch, &mut out, &options).unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::OldOutOfBounds { at, .. }) if at.tuple == 1 && at.old_pos == 102
+        ));
+    }
+}
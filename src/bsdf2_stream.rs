@@ -0,0 +1,668 @@
+// bsdf2_stream.rs - Streaming BSDF2/BSDIFF40 patch application
+
+use std::cell::RefCell;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+use crate::bsdf2::read_header;
+use crate::bsdf2_writer::{CompressionAlgorithm, ControlEntry};
+use crate::checkpoint::Checkpoint;
+use crate::control::TupleValidator;
+use crate::error::{Limit, PatchError, Position, Stream};
+use crate::patch::{PatchLimits, PatchOptions};
+use crate::simd;
+
+/// Size of the working buffers; peak memory does not depend on the input sizes
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A window of the patch file that keeps its own position.
+///
+/// All three streams share one underlying reader, so every read seeks to
+/// where this section left off.
+struct Section<P> {
+    inner: Rc<RefCell<P>>,
+    pos: u64,
+    end: u64,
+}
+
+impl<P: Read + Seek> Read for Section<P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.end - self.pos;
+        if remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let want = buf.len().min(remaining.min(usize::MAX as u64) as usize);
+
+        let mut inner = self.inner.borrow_mut();
+        inner.seek(SeekFrom::Start(self.pos))?;
+        let n = inner.read(&mut buf[..want])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Wrap a compressed section in the matching streaming decoder
+fn decoder<'a, R: Read + 'a>(alg: CompressionAlgorithm, reader: R) -> Box<dyn Read + 'a> {
+    match alg {
+        CompressionAlgorithm::None => Box::new(BufReader::with_capacity(CHUNK_SIZE, reader)),
+        CompressionAlgorithm::Bz2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+        CompressionAlgorithm::Brotli => Box::new(brotli::Decompressor::new(reader, 4096)),
+    }
+}
+
+/// Fill `buf` from a decoded stream, reporting a short stream as corrupt patch data
+fn read_stream(
+    reader: &mut dyn Read,
+    buf: &mut [u8],
+    stream: Stream,
+    at: Position,
+) -> io::Result<()> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            PatchError::StreamExhausted { at, stream }.into()
+        } else {
+            e
+        }
+    })
+}
+
+/// Read one control tuple, returning `false` on a clean end of the control stream
+fn read_control(reader: &mut dyn Read, buf: &mut [u8; 24], at: Position) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => {
+                return Err(PatchError::StreamExhausted {
+                    at,
+                    stream: Stream::Control,
+                }
+                .into())
+            }
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Returns true if the decoded stream has no bytes left
+fn is_drained(reader: &mut dyn Read) -> io::Result<bool> {
+    let mut byte = [0u8; 1];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(n) => return Ok(n == 0),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads `old` at arbitrary positions, treating bytes past its end as zero
+struct OldReader<O> {
+    inner: O,
+    len: u64,
+    pos: Option<u64>,
+}
+
+impl<O: Read + Seek> OldReader<O> {
+    fn new(mut inner: O) -> io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            inner,
+            len,
+            pos: None,
+        })
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let available = self.len.saturating_sub(offset).min(buf.len() as u64) as usize;
+        if available > 0 {
+            if self.pos != Some(offset) {
+                self.inner.seek(SeekFrom::Start(offset))?;
+            }
+            self.pos = None;
+            self.inner.read_exact(&mut buf[..available])?;
+            self.pos = Some(offset + available as u64);
+        }
+        buf[available..].iter_mut().for_each(|b| *b = 0);
+        Ok(())
+    }
+}
+
+/// Apply a BSDF2 or BSDIFF40 patch without holding any of the files in memory.
+///
+/// `old` is read at the positions the control stream asks for, `patch` is read from its
+/// current position to its end, and the new file is written sequentially to `new`.
+/// The control, diff and extra streams are decompressed lazily, so memory use stays
+/// bounded regardless of how large the files are.
+///
+/// Reads past the end of `old` produce zeros, matching `patch_bsdf2` and AOSP bspatch,
+/// unless `PatchOptions::old_bounds` is `Strict`.
+///
+/// No `PatchLimits` apply, since nothing is held in memory; use
+/// `patch_bsdf2_stream_with_options` to cap the output of untrusted patches.
+pub fn patch_bsdf2_stream<O, P, W>(old: O, patch: P, new: W) -> io::Result<()>
+where
+    O: Read + Seek,
+    P: Read + Seek,
+    W: Write,
+{
+    let options = PatchOptions {
+        limits: PatchLimits::unbounded(),
+        ..PatchOptions::default()
+    };
+    patch_bsdf2_stream_with_options(old, patch, new, &options)
+}
+
+/// Streaming patch application with progress reporting, cancellation and limits.
+///
+/// `max_new_size` is checked against the header, and at most `max_stream_size` bytes
+/// are decompressed from each stream. Pass `PatchLimits::unbounded()` to patch images
+/// over the 2 GiB defaults.
+pub fn patch_bsdf2_stream_with_options<O, P, W>(
+    old: O,
+    patch: P,
+    new: W,
+    options: &PatchOptions,
+) -> io::Result<()>
+where
+    O: Read + Seek,
+    P: Read + Seek,
+    W: Write,
+{
+    apply(old, patch, new, options, None, None)
+}
+
+/// Receives each checkpoint together with the interval between them
+type Checkpoints<'a> = (u64, &'a mut dyn FnMut(&Checkpoint) -> io::Result<()>);
+
+/// Streaming patch application that can survive being interrupted.
+///
+/// Works like `patch_bsdf2_stream_with_options`, but after every `interval` bytes of
+/// output it flushes `new` and passes a `Checkpoint` to `on_checkpoint`, which should
+/// persist it (e.g. `Checkpoint::to_bytes` to a file, then fsync). If patching is
+/// interrupted, call again with the last saved checkpoint in `resume` and the same
+/// old file, patch and partially written output: `new` is treated as the whole output
+/// file and is seeked to `resume.new_pos` before writing continues. Data written after
+/// the last checkpoint is simply overwritten.
+pub fn patch_bsdf2_resumable<O, P, W, F>(
+    old: O,
+    patch: P,
+    mut new: W,
+    resume: Option<&Checkpoint>,
+    interval: u64,
+    mut on_checkpoint: F,
+    options: &PatchOptions,
+) -> io::Result<()>
+where
+    O: Read + Seek,
+    P: Read + Seek,
+    W: Write + Seek,
+    F: FnMut(&Checkpoint) -> io::Result<()>,
+{
+    if interval == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Checkpoint interval must be positive",
+        ));
+    }
+    new.seek(SeekFrom::Start(resume.map_or(0, |c| c.new_pos)))?;
+    apply(
+        old,
+        patch,
+        new,
+        options,
+        resume,
+        Some((interval, &mut on_checkpoint)),
+    )
+}
+
+/// Decompress and discard `len` bytes of a stream to reach a checkpoint's re-sync point
+fn skip(reader: &mut dyn Read, len: u64, stream: Stream, at: Position) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(PatchError::StreamExhausted { at, stream }.into());
+    }
+    Ok(())
+}
+
+/// Fail if finishing the current tuple would draw more than `max_stream_size` bytes
+/// from any of the streams
+fn check_streams(state: &Checkpoint, at: Position, limits: &PatchLimits) -> io::Result<()> {
+    let max = limits.max_stream_size;
+    let control = state.tuple.saturating_mul(24);
+    let diff = state.diff_pos.saturating_add(state.add_left);
+    let extra = state.extra_pos.saturating_add(state.copy_left);
+    PatchLimits::check(at, Limit::StreamSize(Stream::Control), max, control)?;
+    PatchLimits::check(at, Limit::StreamSize(Stream::Diff), max, diff)?;
+    PatchLimits::check(at, Limit::StreamSize(Stream::Extra), max, extra)
+}
+
+/// Patch from `resume` (or the start) to the end, emitting checkpoints if asked to
+fn apply<O, P, W>(
+    old: O,
+    mut patch: P,
+    mut new: W,
+    options: &PatchOptions,
+    resume: Option<&Checkpoint>,
+    mut checkpoints: Option<Checkpoints<'_>>,
+) -> io::Result<()>
+where
+    O: Read + Seek,
+    P: Read + Seek,
+    W: Write,
+{
+    let start = patch.stream_position()?;
+    let end = patch.seek(SeekFrom::End(0))?;
+    if end.saturating_sub(start) < 32 {
+        return Err(PatchError::Truncated {
+            at: Position::default(),
+        }
+        .into());
+    }
+
+    let mut raw_header = [0u8; 32];
+    patch.seek(SeekFrom::Start(start))?;
+    patch.read_exact(&mut raw_header)?;
+    let header = read_header(&raw_header, &options.limits)?;
+    // Only checkpoints need the id, so plain streaming does not read the patch twice
+    let patch_id = match (resume, &checkpoints) {
+        (None, None) => 0,
+        _ => Checkpoint::patch_id(&mut patch, start, end)?,
+    };
+
+    let control_start = start + 32;
+    let diff_start = control_start
+        .checked_add(header.ctrl_len)
+        .filter(|&p| p <= end)
+        .ok_or(PatchError::Truncated {
+            at: Position::default(),
+        })?;
+    let extra_start = diff_start
+        .checked_add(header.diff_len)
+        .filter(|&p| p <= end)
+        .ok_or(PatchError::Truncated {
+            at: Position::default(),
+        })?;
+
+    let shared = Rc::new(RefCell::new(patch));
+    let section = |pos, end| Section {
+        inner: Rc::clone(&shared),
+        pos,
+        end,
+    };
+    let mut control = decoder(header.ctrl_alg, section(control_start, diff_start));
+    let mut diff = decoder(header.diff_alg, section(diff_start, extra_start));
+    let mut extra = decoder(header.extra_alg, section(extra_start, end));
+
+    // The state between chunks; the default is the start of the patch
+    let mut state = Checkpoint {
+        patch_id,
+        ..Checkpoint::default()
+    };
+    if let Some(resume) = resume {
+        if resume.patch_id != patch_id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Checkpoint does not belong to this patch",
+            ));
+        }
+        state = *resume;
+        let at = Position {
+            tuple: state.tuple.saturating_sub(1),
+            old_pos: state.old_pos,
+            new_pos: state.new_pos,
+        };
+        let control_pos = state
+            .tuple
+            .checked_mul(24)
+            .ok_or(PatchError::StreamExhausted {
+                at,
+                stream: Stream::Control,
+            })?;
+        skip(&mut control, control_pos, Stream::Control, at)?;
+        skip(&mut diff, state.diff_pos, Stream::Diff, at)?;
+        skip(&mut extra, state.extra_pos, Stream::Extra, at)?;
+    }
+
+    let mut old = OldReader::new(old)?;
+    let new_size = header.new_size;
+    let mut reporter = options.reporter(new_size);
+    let mut next_checkpoint = checkpoints.as_ref().map_or(u64::MAX, |(interval, _)| {
+        state.new_pos.saturating_add(*interval)
+    });
+
+    let mut old_buf = vec![0u8; CHUNK_SIZE];
+    let mut data_buf = vec![0u8; CHUNK_SIZE];
+    let mut ctrl = [0u8; 24];
+    let mut at = Position {
+        tuple: state.tuple.saturating_sub(1),
+        old_pos: state.old_pos,
+        new_pos: state.new_pos,
+    };
+    let mut tuples =
+        TupleValidator::new(Some(new_size)).with_old_bounds(old.len, options.old_bounds);
+    if resume.is_some() {
+        // What is left of the tuple in progress is checked like a tuple of its own
+        tuples = tuples.starting_at(at);
+        tuples.check(ControlEntry {
+            diff_size: i64::try_from(state.add_left).unwrap_or(-1),
+            extra_size: i64::try_from(state.copy_left).unwrap_or(-1),
+            offset_increment: state.seek,
+        })?;
+        check_streams(&state, at, &options.limits)?;
+    }
+
+    loop {
+        // Finish the current tuple one chunk at a time: first its ADD (new = old + diff),
+        // then its COPY (new = extra)
+        while state.add_left > 0 || state.copy_left > 0 {
+            let n = if state.add_left > 0 {
+                let n = state.add_left.min(CHUNK_SIZE as u64) as usize;
+                read_stream(&mut diff, &mut data_buf[..n], Stream::Diff, at)?;
+                old.read_at(state.old_pos, &mut old_buf[..n])?;
+                simd::add_bytes(&mut data_buf[..n], &old_buf[..n]);
+                state.old_pos = state.old_pos.saturating_add(n as u64);
+                state.diff_pos += n as u64;
+                state.add_left -= n as u64;
+                n
+            } else {
+                let n = state.copy_left.min(CHUNK_SIZE as u64) as usize;
+                read_stream(&mut extra, &mut data_buf[..n], Stream::Extra, at)?;
+                state.extra_pos += n as u64;
+                state.copy_left -= n as u64;
+                n
+            };
+            new.write_all(&data_buf[..n])?;
+            state.new_pos += n as u64;
+            reporter.update(state.new_pos)?;
+
+            if state.new_pos >= next_checkpoint {
+                if let Some((interval, on_checkpoint)) = checkpoints.as_mut() {
+                    new.flush()?;
+                    on_checkpoint(&state)?;
+                    next_checkpoint = state.new_pos.saturating_add(*interval);
+                }
+            }
+        }
+
+        // SEEK operation: the validator already applied it to the tuple's end
+        state.old_pos = tuples.at().old_pos;
+        state.seek = 0;
+
+        at = tuples.at();
+        if !read_control(&mut control, &mut ctrl, at)? {
+            break;
+        }
+        state.tuple += 1;
+        PatchLimits::check(
+            at,
+            Limit::ControlTuples,
+            options.limits.max_control_tuples,
+            state.tuple,
+        )?;
+
+        let tuple = tuples.next(&ctrl)?;
+        state.add_left = tuple.add_len;
+        state.copy_left = tuple.copy_len;
+        state.seek = tuple.seek;
+        check_streams(&state, at, &options.limits)?;
+    }
+
+    // Validate final state
+    let written = state.new_pos;
+    tuples.finish()?;
+
+    // Validate all streams were fully consumed
+    if !is_drained(&mut diff)? {
+        return Err(PatchError::UnconsumedData {
+            at,
+            stream: Stream::Diff,
+        }
+        .into());
+    }
+
+    if !is_drained(&mut extra)? {
+        return Err(PatchError::UnconsumedData {
+            at,
+            stream: Stream::Extra,
+        }
+        .into());
+    }
+
+    new.flush()?;
+    reporter.report(written);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsdf2_writer::CompressionAlgorithm as Alg;
+    use crate::patch::OldBoundsMode;
+    use std::io::Cursor;
+
+    fn sample() -> (Vec<u8>, Vec<u8>) {
+        let old: Vec<u8> = (0..60_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        new[1000..1100]
+            .iter_mut()
+            .for_each(|b| *b = b.wrapping_add(3));
+        new.splice(20_000..20_000, (0..5000u32).map(|i| (i % 13) as u8));
+        new.truncate(55_000);
+        (old, new)
+    }
+
+    #[test]
+    fn test_stream_matches_in_memory() {
+        let (old, new) = sample();
+        for alg in [Alg::None, Alg::Bz2, Alg::Brotli] {
+            let mut patch = Vec::new();
+            crate::diff_bsdf2_uniform(&old, &new, &mut patch, alg).unwrap();
+
+            let mut out = Vec::new();
+            patch_bsdf2_stream(Cursor::new(&old), Cursor::new(&patch), &mut out).unwrap();
+            assert_eq!(out, new);
+        }
+    }
+
+    #[test]
+    fn test_stream_patch_at_offset() {
+        let (old, new) = sample();
+        let mut patch = b"prefix".to_vec();
+        crate::diff_bsdiff40(&old, &new, &mut patch).unwrap();
+
+        let mut reader = Cursor::new(&patch);
+        reader.seek(SeekFrom::Start(6)).unwrap();
+        let mut out = Vec::new();
+        patch_bsdf2_stream(Cursor::new(&old), reader, &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn test_stream_truncated_patch() {
+        let (old, new) = sample();
+        let mut patch = Vec::new();
+        crate::diff_bsdf2_uniform(&old, &new, &mut patch, Alg::None).unwrap();
+        patch.truncate(patch.len() - 1);
+
+        let mut out = Vec::new();
+        let err = patch_bsdf2_stream(Cursor::new(&old), Cursor::new(&patch), &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::StreamExhausted {
+                stream: Stream::Extra,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_stream_size_limits() {
+        let (old, new) = sample();
+        let mut patch = Vec::new();
+        crate::diff_bsdf2_uniform(&old, &new, &mut patch, Alg::Bz2).unwrap();
+
+        let limited = |max_new_size, max_stream_size| {
+            let options = PatchOptions {
+                limits: PatchLimits {
+                    max_new_size,
+                    max_stream_size,
+                    ..PatchLimits::default()
+                },
+                ..PatchOptions::default()
+            };
+            let mut out = Vec::new();
+            patch_bsdf2_stream_with_options(
+                Cursor::new(&old),
+                Cursor::new(&patch),
+                &mut out,
+                &options,
+            )
+            .map(|()| out)
+        };
+
+        let err = limited(100, u64::MAX).unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::LimitExceeded {
+                limit: Limit::NewSize,
+                max: 100,
+                ..
+            })
+        ));
+        // The diff stream is drawn on before anything is written past the limit
+        let err = limited(u64::MAX, 100).unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::LimitExceeded {
+                limit: Limit::StreamSize(Stream::Diff),
+                max: 100,
+                ..
+            })
+        ));
+        assert_eq!(limited(new.len() as u64, new.len() as u64).unwrap(), new);
+
+        // Without options nothing is limited
+        let mut out = Vec::new();
+        patch_bsdf2_stream(Cursor::new(&old), Cursor::new(&patch), &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn test_stream_old_bounds_modes() {
+        use crate::bsdf2_writer::{Bsdf2Writer, ControlEntry};
+
+        let old = [10u8, 20, 30, 40];
+        let mut writer = Bsdf2Writer::new(Alg::None, Alg::None, Alg::None);
+        writer
+            .add_control_entry(ControlEntry {
+                diff_size: 6,
+                extra_size: 0,
+                offset_increment: 0,
+            })
+            .unwrap();
+        writer.write_diff_stream(&[1; 6]).unwrap();
+        let mut patch = Vec::new();
+        writer.close(&mut patch).unwrap();
+
+        let mut out = Vec::new();
+        patch_bsdf2_stream(Cursor::new(&old), Cursor::new(&patch), &mut out).unwrap();
+        assert_eq!(out, [11, 21, 31, 41, 1, 1]);
+
+        let options = PatchOptions {
+            old_bounds: OldBoundsMode::Strict,
+            ..PatchOptions::default()
+        };
+        let (old, patch) = (Cursor::new(&old), Cursor::new(&patch));
+        let err = patch_bsdf2_stream_with_options(old, patch, Vec::new(), &options).unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::OldOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let (old, new) = sample();
+        let mut patch = Vec::new();
+        crate::diff_bsdf2_uniform(&old, &new, &mut patch, Alg::Brotli).unwrap();
+        let options = PatchOptions::default();
+
+        // Interrupt the first run after the second checkpoint
+        let mut saved = Vec::new();
+        let mut out = Cursor::new(Vec::new());
+        let err = patch_bsdf2_resumable(
+            Cursor::new(&old),
+            Cursor::new(&patch),
+            &mut out,
+            None,
+            5000,
+            |c| {
+                saved.push(c.to_bytes());
+                match saved.len() {
+                    2 => Err(io::Error::new(io::ErrorKind::Other, "power loss")),
+                    _ => Ok(()),
+                }
+            },
+            &options,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "power loss");
+
+        // Bytes past the checkpoint are garbage after a crash
+        let checkpoint = Checkpoint::from_bytes(&saved[0]).unwrap();
+        let mut partial = out.into_inner();
+        partial[checkpoint.new_pos as usize..].fill(0xAA);
+
+        let mut out = Cursor::new(partial);
+        let mut resumed = 0;
+        patch_bsdf2_resumable(
+            Cursor::new(&old),
+            Cursor::new(&patch),
+            &mut out,
+            Some(&checkpoint),
+            5000,
+            |_| {
+                resumed += 1;
+                Ok(())
+            },
+            &options,
+        )
+        .unwrap();
+        assert!(resumed > 0);
+        assert_eq!(out.into_inner(), new);
+
+        let mut other = Vec::new();
+        crate::diff_bsdiff40(&old, &new, &mut other).unwrap();
+        let err = patch_bsdf2_resumable(
+            Cursor::new(&old),
+            Cursor::new(&other),
+            Cursor::new(Vec::new()),
+            Some(&checkpoint),
+            5000,
+            |_| Ok(()),
+            &options,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Same header, different stream contents: the checkpoint is still rejected
+        let mut edited = patch.clone();
+        *edited.last_mut().unwrap() ^= 1;
+        let err = patch_bsdf2_resumable(
+            Cursor::new(&old),
+            Cursor::new(&edited),
+            Cursor::new(Vec::new()),
+            Some(&checkpoint),
+            5000,
+            |_| Ok(()),
+            &options,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
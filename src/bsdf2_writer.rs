@@ -1,10 +1,13 @@
-use std::io::{self, Write};
 use bzip2::write::BzEncoder;
 use bzip2::Compression as BzCompression;
+use std::io::{self, Write};
 
-const BSDIFF_MAGIC: &[u8; 8] = b"BSDIFF40";
-const BSDF2_MAGIC: &[u8; 5] = b"BSDF2";
+use crate::bsdf2::Bsdf2Header;
+use crate::error::{PatchError, Stream};
+use crate::format::PatchFormat;
+use crate::sink::ControlSink;
 
+/// Codec of one BSDF2 stream, as stored in the codec bytes of the magic
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CompressionAlgorithm {
     None = 0,
@@ -12,11 +15,90 @@ pub enum CompressionAlgorithm {
     Brotli = 2,
 }
 
-fn compress(alg: CompressionAlgorithm, data: &[u8]) -> io::Result<Vec<u8>> {
+impl CompressionAlgorithm {
+    pub(crate) fn from_u8(value: u8, stream: Stream) -> Result<Self, PatchError> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Bz2),
+            2 => Ok(Self::Brotli),
+            _ => Err(PatchError::UnknownCodec { stream, value }),
+        }
+    }
+}
+
+/// Android's `kBrotliDefaultLgwin`
+pub const BROTLI_DEFAULT_LGWIN: u32 = 20;
+
+/// Largest accepted `CompressionParams::brotli_buffer_size`, 16 MiB
+pub const BROTLI_MAX_BUFFER_SIZE: usize = 1 << 24;
+
+/// Compressor settings for one patch stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionParams {
+    /// bzip2 block size level, 1-9. Default: 9.
+    pub bz2_level: u32,
+    /// Brotli quality, 0-11. Lower is much faster on large streams. Default: 11.
+    pub brotli_quality: u32,
+    /// Brotli window size as a power of two, 10-24. Default: `BROTLI_DEFAULT_LGWIN`.
+    pub brotli_lgwin: u32,
+    /// Size of the Brotli encoder's buffer in bytes, 1 to `BROTLI_MAX_BUFFER_SIZE`.
+    /// Default: 4096.
+    pub brotli_buffer_size: usize,
+}
+
+impl Default for CompressionParams {
+    fn default() -> Self {
+        Self {
+            bz2_level: 9,
+            brotli_quality: 11,
+            brotli_lgwin: BROTLI_DEFAULT_LGWIN,
+            brotli_buffer_size: 4096,
+        }
+    }
+}
+
+impl CompressionParams {
+    /// Check the settings `alg` uses; the others are ignored
+    fn validate(&self, alg: CompressionAlgorithm) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        match alg {
+            CompressionAlgorithm::None => {}
+            CompressionAlgorithm::Bz2 => {
+                if !(1..=9).contains(&self.bz2_level) {
+                    return invalid(format!("bzip2 level {} is not in 1..=9", self.bz2_level));
+                }
+            }
+            CompressionAlgorithm::Brotli => {
+                if self.brotli_quality > 11 {
+                    let quality = self.brotli_quality;
+                    return invalid(format!("Brotli quality {} is not in 0..=11", quality));
+                }
+                if !(10..=24).contains(&self.brotli_lgwin) {
+                    let lgwin = self.brotli_lgwin;
+                    return invalid(format!("Brotli lgwin {} is not in 10..=24", lgwin));
+                }
+                if !(1..=BROTLI_MAX_BUFFER_SIZE).contains(&self.brotli_buffer_size) {
+                    return invalid(format!(
+                        "Brotli buffer size {} is not in 1..={}",
+                        self.brotli_buffer_size, BROTLI_MAX_BUFFER_SIZE
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn compress(
+    alg: CompressionAlgorithm,
+    params: &CompressionParams,
+    data: &[u8],
+) -> io::Result<Vec<u8>> {
+    params.validate(alg)?;
     match alg {
         CompressionAlgorithm::None => Ok(data.to_vec()),
         CompressionAlgorithm::Bz2 => {
-            let mut encoder = BzEncoder::new(Vec::new(), BzCompression::best());
+            let mut encoder = BzEncoder::new(Vec::new(), BzCompression::new(params.bz2_level));
             encoder.write_all(data)?;
             encoder.finish()
         }
@@ -25,9 +107,9 @@ fn compress(alg: CompressionAlgorithm, data: &[u8]) -> io::Result<Vec<u8>> {
             {
                 let mut encoder = brotli::CompressorWriter::new(
                     &mut compressed,
-                    4096,  // buffer size
-                    11,    // quality (11 = max)
-                    20,    // lg_window_size (matches Android kBrotliDefaultLgwin)
+                    params.brotli_buffer_size,
+                    params.brotli_quality,
+                    params.brotli_lgwin,
                 );
                 encoder.write_all(data)?;
                 encoder.flush()?;
@@ -37,9 +119,35 @@ fn compress(alg: CompressionAlgorithm, data: &[u8]) -> io::Result<Vec<u8>> {
     }
 }
 
+/// Compress `data` with every algorithm in `allowed`, keeping the smallest result.
+///
+/// Ties go to the algorithm listed first.
+fn compress_smallest(
+    allowed: &[CompressionAlgorithm],
+    params: &CompressionParams,
+    data: &[u8],
+) -> io::Result<(CompressionAlgorithm, Vec<u8>)> {
+    let mut best: Option<(CompressionAlgorithm, Vec<u8>)> = None;
+    for &alg in allowed {
+        let compressed = compress(alg, params, data)?;
+        if best
+            .as_ref()
+            .map_or(true, |(_, b)| compressed.len() < b.len())
+        {
+            best = Some((alg, compressed));
+        }
+    }
+    best.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No compression algorithm allowed",
+        )
+    })
+}
+
 /// encode signed integer in bspatch sign-magnitude format
 #[inline]
-fn encode_int64(x: i64, buf: &mut [u8]) {
+pub(crate) fn encode_int64(x: i64, buf: &mut [u8]) {
     if x >= 0 {
         buf.copy_from_slice(&x.to_le_bytes());
     } else {
@@ -49,7 +157,7 @@ fn encode_int64(x: i64, buf: &mut [u8]) {
 }
 
 /// Control entry matching
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ControlEntry {
     pub diff_size: i64,
     pub extra_size: i64,
@@ -64,7 +172,12 @@ pub struct Bsdf2Writer {
     ctrl_alg: CompressionAlgorithm,
     diff_alg: CompressionAlgorithm,
     extra_alg: CompressionAlgorithm,
+    ctrl_params: CompressionParams,
+    diff_params: CompressionParams,
+    extra_params: CompressionParams,
     written_output: u64,
+    /// Algorithms to try for each stream when picking codecs automatically
+    auto_algs: Option<Vec<CompressionAlgorithm>>,
 }
 
 impl Bsdf2Writer {
@@ -81,9 +194,38 @@ impl Bsdf2Writer {
             ctrl_alg,
             diff_alg,
             extra_alg,
+            ctrl_params: CompressionParams::default(),
+            diff_params: CompressionParams::default(),
+            extra_params: CompressionParams::default(),
             written_output: 0,
+            auto_algs: None,
+        }
+    }
+
+    /// Create a writer that picks the codec of each stream on `close`.
+    ///
+    /// Every stream is compressed with each algorithm in `allowed` and the smallest
+    /// result is kept, so restricting `allowed` keeps patches readable by older
+    /// patchers (e.g. `&[Bz2]` always yields a BSDIFF40 patch).
+    pub fn new_auto(allowed: &[CompressionAlgorithm]) -> Self {
+        Self {
+            auto_algs: Some(allowed.to_vec()),
+            ..Self::new_legacy()
         }
     }
+
+    /// Set the compressor parameters for the control, diff and extra streams
+    pub fn with_params(
+        mut self,
+        ctrl: CompressionParams,
+        diff: CompressionParams,
+        extra: CompressionParams,
+    ) -> Self {
+        self.ctrl_params = ctrl;
+        self.diff_params = diff;
+        self.extra_params = extra;
+        self
+    }
     pub fn new_legacy() -> Self {
         Self::new(
             CompressionAlgorithm::Bz2,
@@ -91,6 +233,7 @@ impl Bsdf2Writer {
             CompressionAlgorithm::Bz2,
         )
     }
+
     pub fn add_control_entry(&mut self, entry: ControlEntry) -> io::Result<()> {
         let mut buf = [0u8; 24];
         encode_int64(entry.diff_size, &mut buf[0..8]);
@@ -113,21 +256,45 @@ impl Bsdf2Writer {
     }
     pub fn close<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
         // Compress all streams
-        let ctrl_compressed = compress(self.ctrl_alg, &self.ctrl_data)?;
-        let diff_compressed = compress(self.diff_alg, &self.diff_data)?;
-        let extra_compressed = compress(self.extra_alg, &self.extra_data)?;
+        let (ctrl_compressed, diff_compressed, extra_compressed) = match &self.auto_algs {
+            Some(allowed) => {
+                let (ctrl_alg, ctrl) =
+                    compress_smallest(allowed, &self.ctrl_params, &self.ctrl_data)?;
+                let (diff_alg, diff) =
+                    compress_smallest(allowed, &self.diff_params, &self.diff_data)?;
+                let (extra_alg, extra) =
+                    compress_smallest(allowed, &self.extra_params, &self.extra_data)?;
+                self.ctrl_alg = ctrl_alg;
+                self.diff_alg = diff_alg;
+                self.extra_alg = extra_alg;
+                (ctrl, diff, extra)
+            }
+            None => (
+                compress(self.ctrl_alg, &self.ctrl_params, &self.ctrl_data)?,
+                compress(self.diff_alg, &self.diff_params, &self.diff_data)?,
+                compress(self.extra_alg, &self.extra_params, &self.extra_data)?,
+            ),
+        };
 
         // Write header
         let is_legacy = self.ctrl_alg == CompressionAlgorithm::Bz2
             && self.diff_alg == CompressionAlgorithm::Bz2
             && self.extra_alg == CompressionAlgorithm::Bz2;
 
-        self.write_header(
-            writer,
-            is_legacy,
-            ctrl_compressed.len() as u64,
-            diff_compressed.len() as u64,
-        )?;
+        Bsdf2Header {
+            format: if is_legacy {
+                PatchFormat::Bsdiff40
+            } else {
+                PatchFormat::Bsdf2
+            },
+            ctrl_alg: self.ctrl_alg,
+            diff_alg: self.diff_alg,
+            extra_alg: self.extra_alg,
+            ctrl_len: ctrl_compressed.len() as u64,
+            diff_len: diff_compressed.len() as u64,
+            new_size: self.written_output,
+        }
+        .write_to(writer)?;
 
         // Write compressed streams
         writer.write_all(&ctrl_compressed)?;
@@ -136,37 +303,26 @@ impl Bsdf2Writer {
 
         Ok(())
     }
+}
 
-    fn write_header<W: Write>(
-        &self,
-        writer: &mut W,
-        is_legacy: bool,
-        ctrl_size: u64,
-        diff_size: u64,
-    ) -> io::Result<()> {
-        let mut header = [0u8; 32];
-
-        if is_legacy {
-            header[0..8].copy_from_slice(BSDIFF_MAGIC);
-        } else {
-            header[0..5].copy_from_slice(BSDF2_MAGIC);
-            header[5] = self.ctrl_alg as u8;
-            header[6] = self.diff_alg as u8;
-            header[7] = self.extra_alg as u8;
-        }
+impl ControlSink for Bsdf2Writer {
+    fn control(&mut self, entry: ControlEntry) -> io::Result<()> {
+        self.add_control_entry(entry)
+    }
 
-        encode_int64(ctrl_size as i64, &mut header[8..16]);
-        encode_int64(diff_size as i64, &mut header[16..24]);
-        encode_int64(self.written_output as i64, &mut header[24..32]);
+    fn diff(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_diff_stream(bytes)
+    }
 
-        writer.write_all(&header)?;
-        Ok(())
+    fn extra(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_extra_stream(bytes)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Read;
 
     #[test]
     fn test_encode_int64_positive() {
@@ -200,6 +356,79 @@ mod tests {
         assert_eq!(writer.written_output, 0);
     }
 
+    #[test]
+    fn test_params_roundtrip_and_validation() {
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 37) as u8).collect();
+        let fast = CompressionParams {
+            bz2_level: 1,
+            brotli_quality: 3,
+            brotli_lgwin: 22,
+            brotli_buffer_size: 1 << 16,
+        };
+        for alg in [CompressionAlgorithm::Bz2, CompressionAlgorithm::Brotli] {
+            let compressed = compress(alg, &fast, &data).unwrap();
+            let mut out = Vec::new();
+            match alg {
+                CompressionAlgorithm::Bz2 => {
+                    bzip2::read::BzDecoder::new(&compressed[..]).read_to_end(&mut out)
+                }
+                _ => brotli::Decompressor::new(&compressed[..], 4096).read_to_end(&mut out),
+            }
+            .unwrap();
+            assert_eq!(out, data);
+        }
+
+        let bad = CompressionParams {
+            brotli_lgwin: 30,
+            ..CompressionParams::default()
+        };
+        let err = compress(CompressionAlgorithm::Brotli, &bad, &data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let no_buffer = CompressionParams {
+            brotli_buffer_size: 0,
+            ..CompressionParams::default()
+        };
+        assert!(compress(CompressionAlgorithm::Brotli, &no_buffer, &data).is_err());
+
+        // Only the settings of the algorithm in use are checked
+        assert!(compress(CompressionAlgorithm::Bz2, &bad, &data).is_ok());
+        let bad_bz2 = CompressionParams {
+            bz2_level: 0,
+            ..CompressionParams::default()
+        };
+        assert!(compress(CompressionAlgorithm::Bz2, &bad_bz2, &data).is_err());
+        assert!(compress(CompressionAlgorithm::Brotli, &bad_bz2, &data).is_ok());
+        let all_bad = CompressionParams {
+            bz2_level: 0,
+            brotli_buffer_size: 0,
+            ..bad
+        };
+        assert!(compress(CompressionAlgorithm::None, &all_bad, &data).is_ok());
+    }
+
+    #[test]
+    fn test_compress_smallest() {
+        let params = CompressionParams::default();
+        let all = [
+            CompressionAlgorithm::None,
+            CompressionAlgorithm::Bz2,
+            CompressionAlgorithm::Brotli,
+        ];
+
+        // A handful of bytes never shrinks under bzip2's block overhead
+        let (alg, _) = compress_smallest(&all[..2], &params, &[1, 2, 3]).unwrap();
+        assert_eq!(alg, CompressionAlgorithm::None);
+
+        let repetitive = vec![7u8; 10_000];
+        let (alg, data) = compress_smallest(&all, &params, &repetitive).unwrap();
+        assert_ne!(alg, CompressionAlgorithm::None);
+        for other in all {
+            assert!(data.len() <= compress(other, &params, &repetitive).unwrap().len());
+        }
+
+        assert!(compress_smallest(&[], &params, &repetitive).is_err());
+    }
+
     #[test]
     fn test_legacy_writer() {
         let writer = Bsdf2Writer::new_legacy();
@@ -0,0 +1,135 @@
+// checkpoint.rs - Serializable state for resuming an interrupted streaming patch
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::suffix::content_hash;
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"BSDCKPT1";
+
+/// Where a streaming patch stopped, so it can continue after a reboot.
+///
+/// Decompressor state cannot be saved, so a checkpoint records re-sync points
+/// instead: how many decompressed bytes of each stream were used. Resuming
+/// decompresses and skips that many bytes, then continues the control tuple that
+/// was in progress. `Checkpoint::default()` is the start of the patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Checkpoint {
+    /// Hash of the whole patch, header and streams, to catch checkpoints of another patch
+    pub patch_id: u64,
+    /// Number of control tuples read, including the one in progress
+    pub tuple: u64,
+    /// Read position in old
+    pub old_pos: u64,
+    /// Bytes of the new file written and flushed
+    pub new_pos: u64,
+    /// Decompressed bytes of the diff stream used
+    pub diff_pos: u64,
+    /// Decompressed bytes of the extra stream used
+    pub extra_pos: u64,
+    /// ADD bytes of the current tuple not yet written
+    pub add_left: u64,
+    /// COPY bytes of the current tuple not yet written
+    pub copy_left: u64,
+    /// Seek of the current tuple, applied once its ADD and COPY are done
+    pub seek: i64,
+}
+
+impl Checkpoint {
+    /// Size of the serialized checkpoint
+    pub const SIZE: usize = 88;
+
+    /// Identify the patch in `patch[start..end]` by hashing all of it, so patches
+    /// that share a header but differ in their streams get different ids
+    pub(crate) fn patch_id<P>(patch: &mut P, start: u64, end: u64) -> io::Result<u64>
+    where
+        P: Read + Seek,
+    {
+        patch.seek(SeekFrom::Start(start))?;
+        let mut id = content_hash(&(end - start).to_le_bytes());
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut left = end - start;
+        while left > 0 {
+            let chunk = &mut buf[..left.min(64 * 1024) as usize];
+            patch.read_exact(chunk)?;
+            // Chain the hash of each chunk onto the hash so far
+            let mut link = [0u8; 16];
+            link[..8].copy_from_slice(&id.to_le_bytes());
+            link[8..].copy_from_slice(&content_hash(chunk).to_le_bytes());
+            id = content_hash(&link);
+            left -= chunk.len() as u64;
+        }
+        Ok(id)
+    }
+
+    /// Serialize to a fixed-size little-endian record ending with a checksum
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        out[..8].copy_from_slice(CHECKPOINT_MAGIC);
+        let fields = [
+            self.patch_id,
+            self.tuple,
+            self.old_pos,
+            self.new_pos,
+            self.diff_pos,
+            self.extra_pos,
+            self.add_left,
+            self.copy_left,
+            self.seek as u64,
+        ];
+        for (chunk, field) in out[8..80].chunks_exact_mut(8).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        let checksum = content_hash(&out[..80]);
+        out[80..].copy_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Deserialize a checkpoint, rejecting torn or foreign records
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if data.len() != Self::SIZE || &data[..8] != CHECKPOINT_MAGIC {
+            return Err(invalid("Not a patch checkpoint"));
+        }
+        let field = |i: usize| u64::from_le_bytes(data[8 + i * 8..16 + i * 8].try_into().unwrap());
+        if field(9) != content_hash(&data[..80]) {
+            return Err(invalid("Checkpoint checksum mismatch"));
+        }
+        Ok(Self {
+            patch_id: field(0),
+            tuple: field(1),
+            old_pos: field(2),
+            new_pos: field(3),
+            diff_pos: field(4),
+            extra_pos: field(5),
+            add_left: field(6),
+            copy_left: field(7),
+            seek: field(8) as i64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let checkpoint = Checkpoint {
+            patch_id: 0xDEAD_BEEF,
+            tuple: 7,
+            old_pos: 1 << 40,
+            new_pos: 123_456,
+            diff_pos: 100_000,
+            extra_pos: 23_456,
+            add_left: 9,
+            copy_left: 0,
+            seek: -42,
+        };
+        let mut bytes = checkpoint.to_bytes();
+        assert_eq!(Checkpoint::from_bytes(&bytes).unwrap(), checkpoint);
+
+        bytes[20] ^= 1;
+        assert!(Checkpoint::from_bytes(&bytes).is_err());
+        assert!(Checkpoint::from_bytes(&bytes[..40]).is_err());
+    }
+}
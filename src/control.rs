@@ -0,0 +1,318 @@
+// control.rs - Decoding and checking control tuples, shared by every patcher
+
+use crate::bsdf2::offtin;
+use crate::bsdf2_writer::ControlEntry;
+use crate::error::{PatchError, Position, Stream};
+use crate::patch::OldBoundsMode;
+
+/// `base + delta`, or `None` if the result is negative or does not fit in a `u64`
+pub(crate) fn add_signed(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
+
+/// A control tuple that passed validation, with the offsets it starts at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CheckedTuple {
+    /// Index of the tuple and the old/new offsets at which it starts
+    pub(crate) at: Position,
+    pub(crate) add_len: u64,
+    pub(crate) copy_len: u64,
+    pub(crate) seek: i64,
+    /// Offset of the tuple's ADD bytes in the diff stream
+    pub(crate) diff_pos: u64,
+    /// Offset of the tuple's COPY bytes in the extra stream
+    pub(crate) extra_pos: u64,
+}
+
+/// Walks the control tuples of a patch, checking each against what is known about
+/// the new size, the diff and extra streams and the old file.
+///
+/// Every patcher and `Tuples` go through this, so a malformed patch fails with the
+/// same `PatchError` at the same tuple whichever way it is applied. Checks whose
+/// inputs are unknown (a raw patch has no new size, a streamed patch no stream
+/// lengths) are skipped; the caller finds those problems as it reads.
+#[derive(Debug, Clone)]
+pub(crate) struct TupleValidator {
+    /// Start of the next tuple
+    at: Position,
+    new_size: Option<u64>,
+    /// Length of old, only when reads past its end are errors
+    old_len: Option<u64>,
+    diff_pos: u64,
+    extra_pos: u64,
+    diff_len: Option<u64>,
+    extra_len: Option<u64>,
+}
+
+impl TupleValidator {
+    pub(crate) fn new(new_size: Option<u64>) -> Self {
+        Self {
+            at: Position::default(),
+            new_size,
+            old_len: None,
+            diff_pos: 0,
+            extra_pos: 0,
+            diff_len: None,
+            extra_len: None,
+        }
+    }
+
+    /// Check ADDs and COPYs against the lengths of the decompressed streams
+    pub(crate) fn with_streams(mut self, diff_len: u64, extra_len: u64) -> Self {
+        self.diff_len = Some(diff_len);
+        self.extra_len = Some(extra_len);
+        self
+    }
+
+    /// Check ADDs against the end of old if `mode` is `Strict`
+    pub(crate) fn with_old_bounds(mut self, old_len: u64, mode: OldBoundsMode) -> Self {
+        self.old_len = match mode {
+            OldBoundsMode::Strict => Some(old_len),
+            OldBoundsMode::AospZeroFill => None,
+        };
+        self
+    }
+
+    /// Continue a walk that was interrupted at `at`
+    pub(crate) fn starting_at(mut self, at: Position) -> Self {
+        self.at = at;
+        self
+    }
+
+    /// Where the next tuple starts, or where the patch ends once all are checked
+    pub(crate) fn at(&self) -> Position {
+        self.at
+    }
+
+    /// Decode the next 24-byte tuple in bspatch sign-magnitude encoding and check it
+    pub(crate) fn next(&mut self, ctrl: &[u8; 24]) -> Result<CheckedTuple, PatchError> {
+        self.check(ControlEntry {
+            diff_size: offtin(ctrl[0..8].try_into().unwrap()),
+            extra_size: offtin(ctrl[8..16].try_into().unwrap()),
+            offset_increment: offtin(ctrl[16..24].try_into().unwrap()),
+        })
+    }
+
+    /// Check the next tuple and move past it
+    pub(crate) fn check(&mut self, entry: ControlEntry) -> Result<CheckedTuple, PatchError> {
+        let at = self.at;
+        let seek = entry.offset_increment;
+        if entry.diff_size < 0 || entry.extra_size < 0 {
+            return Err(PatchError::NegativeLength {
+                at,
+                value: entry.diff_size.min(entry.extra_size),
+            });
+        }
+        let (add_len, copy_len) = (entry.diff_size as u64, entry.extra_size as u64);
+
+        // Saturating, so an overflowing tuple is also too big for any new size limit
+        let new_end = at.new_pos.saturating_add(add_len).saturating_add(copy_len);
+        if let Some(expected) = self.new_size.filter(|&size| new_end > size) {
+            return Err(PatchError::SizeMismatch {
+                at,
+                expected,
+                actual: new_end,
+            });
+        }
+
+        let exceeds = |pos: u64, len: u64, limit: Option<u64>| {
+            limit.map_or(false, |limit| {
+                pos.checked_add(len).map_or(true, |end| end > limit)
+            })
+        };
+        if add_len > 0 {
+            if exceeds(self.diff_pos, add_len, self.diff_len) {
+                return Err(PatchError::StreamExhausted {
+                    at,
+                    stream: Stream::Diff,
+                });
+            }
+            if let Some(old_len) = self.old_len {
+                if exceeds(at.old_pos, add_len, Some(old_len)) {
+                    return Err(PatchError::OldOutOfBounds {
+                        at,
+                        len: add_len,
+                        old_len,
+                    });
+                }
+            }
+        }
+        if copy_len > 0 && exceeds(self.extra_pos, copy_len, self.extra_len) {
+            return Err(PatchError::StreamExhausted {
+                at,
+                stream: Stream::Extra,
+            });
+        }
+
+        // The seek applies from the end of the ADD
+        let old_pos = i64::try_from(at.old_pos.saturating_add(add_len))
+            .ok()
+            .and_then(|pos| pos.checked_add(seek))
+            .ok_or(PatchError::SeekOverflow { at, seek })?;
+        if old_pos < 0 {
+            return Err(PatchError::SeekUnderflow { at, seek });
+        }
+
+        let tuple = CheckedTuple {
+            at,
+            add_len,
+            copy_len,
+            seek,
+            diff_pos: self.diff_pos,
+            extra_pos: self.extra_pos,
+        };
+        self.at = Position {
+            tuple: at.tuple + 1,
+            old_pos: old_pos as u64,
+            new_pos: new_end,
+        };
+        self.diff_pos += add_len;
+        self.extra_pos += copy_len;
+        Ok(tuple)
+    }
+
+    /// Checks made once the control stream is used up: the new size is complete and
+    /// no diff or extra bytes are left over
+    pub(crate) fn finish(&self) -> Result<(), PatchError> {
+        let at = self.at;
+        if let Some(expected) = self.new_size.filter(|&size| size != at.new_pos) {
+            return Err(PatchError::SizeMismatch {
+                at,
+                expected,
+                actual: at.new_pos,
+            });
+        }
+        if self.diff_len.map_or(false, |len| len != self.diff_pos) {
+            return Err(PatchError::UnconsumedData {
+                at,
+                stream: Stream::Diff,
+            });
+        }
+        if self.extra_len.map_or(false, |len| len != self.extra_pos) {
+            return Err(PatchError::UnconsumedData {
+                at,
+                stream: Stream::Extra,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(diff_size: i64, extra_size: i64, offset_increment: i64) -> ControlEntry {
+        ControlEntry {
+            diff_size,
+            extra_size,
+            offset_increment,
+        }
+    }
+
+    #[test]
+    fn test_checks_in_order() {
+        let mut tuples = TupleValidator::new(Some(10))
+            .with_streams(6, 4)
+            .with_old_bounds(8, OldBoundsMode::Strict);
+        let first = tuples.check(entry(4, 2, -1)).unwrap();
+        assert_eq!((first.diff_pos, first.extra_pos), (0, 0));
+        assert_eq!(
+            tuples.at(),
+            Position {
+                tuple: 1,
+                old_pos: 3,
+                new_pos: 6
+            }
+        );
+
+        // Try each bad tuple on a copy of the walk so far
+        let err = |tuples: &TupleValidator, e| tuples.clone().check(e).unwrap_err();
+        assert!(matches!(
+            err(&tuples, entry(-1, 0, 0)),
+            PatchError::NegativeLength { .. }
+        ));
+        assert!(matches!(
+            err(&tuples, entry(2, 3, 0)),
+            PatchError::SizeMismatch { .. }
+        ));
+        assert!(matches!(
+            err(&tuples, entry(3, 0, 0)),
+            PatchError::StreamExhausted {
+                stream: Stream::Diff,
+                ..
+            }
+        ));
+        assert!(matches!(
+            err(&tuples, entry(0, 3, 0)),
+            PatchError::StreamExhausted {
+                stream: Stream::Extra,
+                ..
+            }
+        ));
+        assert!(matches!(
+            err(&tuples, entry(2, 0, -6)),
+            PatchError::SeekUnderflow { .. }
+        ));
+        assert!(matches!(
+            tuples.finish(),
+            Err(PatchError::SizeMismatch { .. })
+        ));
+
+        let last = tuples.check(entry(2, 2, 0)).unwrap();
+        assert_eq!((last.diff_pos, last.extra_pos), (4, 2));
+        tuples.finish().unwrap();
+
+        // Reads past the end of old are only checked in strict mode
+        let mut strict = TupleValidator::new(None).with_old_bounds(2, OldBoundsMode::Strict);
+        assert!(matches!(
+            strict.check(entry(3, 0, 0)),
+            Err(PatchError::OldOutOfBounds { .. })
+        ));
+        let mut lenient = TupleValidator::new(None).with_old_bounds(2, OldBoundsMode::AospZeroFill);
+        lenient.check(entry(3, 0, 0)).unwrap();
+        lenient.finish().unwrap();
+    }
+
+    #[test]
+    fn test_patchers_agree_on_errors() {
+        use crate::bsdf2_writer::{Bsdf2Writer, CompressionAlgorithm as Alg};
+        use std::io::Cursor;
+
+        // The second tuple seeks before the start of old
+        let old = [7u8; 64];
+        let mut writer = Bsdf2Writer::new(Alg::None, Alg::None, Alg::None);
+        writer.add_control_entry(entry(8, 0, 0)).unwrap();
+        writer.add_control_entry(entry(8, 0, -40)).unwrap();
+        writer.write_diff_stream(&[1; 16]).unwrap();
+        let mut patch = Vec::new();
+        writer.close(&mut patch).unwrap();
+
+        let expected = PatchError::SeekUnderflow {
+            at: Position {
+                tuple: 1,
+                old_pos: 8,
+                new_pos: 8,
+            },
+            seek: -40,
+        };
+        let errors = [
+            crate::patch_bsdf2(&old, &patch, &mut Vec::new()).unwrap_err(),
+            crate::patch_bsdf2_parallel(&old, &patch, &mut Vec::new(), 2).unwrap_err(),
+            crate::patch_bsdf2_stream(Cursor::new(&old), Cursor::new(&patch), Vec::new())
+                .unwrap_err(),
+            crate::PatchReader::new(&patch)
+                .unwrap()
+                .tuples()
+                .find_map(Result::err)
+                .unwrap(),
+        ];
+        for err in &errors {
+            assert_eq!(crate::patch_error(err), Some(&expected));
+        }
+    }
+}
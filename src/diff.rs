@@ -28,25 +28,118 @@
  * POSSIBILITY OF SUCH DAMAGE.
  */
 
-use std::cmp::Ordering;
+use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{BufWriter, Write};
+use std::path::Path;
 
-use crate::bsdf2_writer::{Bsdf2Writer, CompressionAlgorithm, ControlEntry};
+use crate::bsdf2_writer::{Bsdf2Writer, CompressionAlgorithm, CompressionParams, ControlEntry};
+use crate::endsley::EndsleyWriter;
+use crate::inplace::make_in_place_safe;
+use crate::progress::{CancelToken, Phase, ProgressCallback, Reporter};
+use crate::sink::{ControlSink, RawWriter};
+use crate::suffix::SuffixArray;
 
-/// Diff an "old" and a "new" file, returning a legacy BSDIFF40 patch.
-/// 
-/// This maintains backward compatibility - generates classic BZ2-compressed patches
-/// that are identical to the original bsdiff implementation.
+/// Tuning knobs for the bsdiff scan loop.
+///
+/// The defaults reproduce classic bsdiff output exactly.
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    /// Shortest match the scan will jump to, like `min_length` in AOSP's bsdiff.
+    /// Shorter matches are folded into the surrounding diff or extra data.
+    /// Default: 0 (any length).
+    pub min_match_len: usize,
+    /// How many more matching bytes a new match must have than the current
+    /// alignment before the scan switches to it. Default: 8.
+    pub mismatch_threshold: usize,
+    /// Whether to grow each match backwards into the preceding extra data.
+    /// Disabling this is faster but produces larger patches. Default: true.
+    pub extend_backward: bool,
+    /// Receives `Phase::SuffixSort` and then `Phase::Scan` progress. Default: none.
+    pub progress: Option<ProgressCallback>,
+    /// Aborts the diff with a `Cancelled` error when triggered. Default: none.
+    pub cancel: Option<CancelToken>,
+    /// Compressor settings for the control stream of BSDIFF40 and BSDF2 patches,
+    /// and for the single stream of ENDSLEY patches
+    pub ctrl_params: CompressionParams,
+    /// Compressor settings for the diff stream of BSDIFF40 and BSDF2 patches
+    pub diff_params: CompressionParams,
+    /// Compressor settings for the extra stream of BSDIFF40 and BSDF2 patches
+    pub extra_params: CompressionParams,
+    /// Make patches that `patch_bsdf2_in_place` can apply with at most this many
+    /// bytes of scratch. Read/write cycles beyond that are broken by sending the
+    /// smallest conflicting matches as extra data instead. Default: none.
+    pub in_place_scratch: Option<u64>,
+}
+
+impl DiffOptions {
+    fn writer_params(&self, writer: Bsdf2Writer) -> Bsdf2Writer {
+        writer.with_params(self.ctrl_params, self.diff_params, self.extra_params)
+    }
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            min_match_len: 0,
+            mismatch_threshold: 8,
+            extend_backward: true,
+            progress: None,
+            cancel: None,
+            ctrl_params: CompressionParams::default(),
+            diff_params: CompressionParams::default(),
+            extra_params: CompressionParams::default(),
+            in_place_scratch: None,
+        }
+    }
+}
+
+/// Diff an "old" and a "new" file, returning a raw patch.
+///
+/// The raw format is the uncompressed control/diff/extra stream without a header,
+/// as read by `patch()`. Use `diff_bsdiff40` for a classic BZ2-compressed patch.
 pub fn diff<T: Write>(old: &[u8], new: &[u8], writer: &mut T) -> io::Result<()> {
-    bsdiff_internal(old, new, writer)
+    diff_with_options(old, new, writer, &DiffOptions::default())
+}
+
+/// Generate a raw patch with custom scan options
+pub fn diff_with_options<T: Write>(
+    old: &[u8],
+    new: &[u8],
+    writer: &mut T,
+    options: &DiffOptions,
+) -> io::Result<()> {
+    DiffIndex::new_with_options(old, options)?.diff_with_options(new, writer, options)
 }
 
 /// Generate a legacy BSDIFF40 patch (BZ2 compressed)
 pub fn diff_bsdiff40<T: Write>(old: &[u8], new: &[u8], writer: &mut T) -> io::Result<()> {
-    let mut patch_writer = Bsdf2Writer::new_legacy();
-    bsdiff_with_writer(old, new, &mut patch_writer)?;
-    patch_writer.close(writer)
+    diff_bsdiff40_with_options(old, new, writer, &DiffOptions::default())
+}
+
+/// Generate a legacy BSDIFF40 patch with custom scan options
+pub fn diff_bsdiff40_with_options<T: Write>(
+    old: &[u8],
+    new: &[u8],
+    writer: &mut T,
+    options: &DiffOptions,
+) -> io::Result<()> {
+    DiffIndex::new_with_options(old, options)?.diff_bsdiff40_with_options(new, writer, options)
+}
+
+/// Generate an `ENDSLEY/BSDIFF43` patch, the format of Matthew Endsley's bsdiff library
+pub fn diff_endsley<T: Write>(old: &[u8], new: &[u8], writer: &mut T) -> io::Result<()> {
+    diff_endsley_with_options(old, new, writer, &DiffOptions::default())
+}
+
+/// Generate an `ENDSLEY/BSDIFF43` patch with custom scan options
+pub fn diff_endsley_with_options<T: Write>(
+    old: &[u8],
+    new: &[u8],
+    writer: &mut T,
+    options: &DiffOptions,
+) -> io::Result<()> {
+    DiffIndex::new_with_options(old, options)?.diff_endsley_with_options(new, writer, options)
 }
 
 /// Generate a BSDF2 patch with specified compression algorithms
@@ -58,9 +151,61 @@ pub fn diff_bsdf2<T: Write>(
     diff_alg: CompressionAlgorithm,
     extra_alg: CompressionAlgorithm,
 ) -> io::Result<()> {
-    let mut patch_writer = Bsdf2Writer::new(ctrl_alg, diff_alg, extra_alg);
-    bsdiff_with_writer(old, new, &mut patch_writer)?;
-    patch_writer.close(writer)
+    diff_bsdf2_with_options(
+        old,
+        new,
+        writer,
+        ctrl_alg,
+        diff_alg,
+        extra_alg,
+        &DiffOptions::default(),
+    )
+}
+
+/// Generate a BSDF2 patch with custom scan options
+pub fn diff_bsdf2_with_options<T: Write>(
+    old: &[u8],
+    new: &[u8],
+    writer: &mut T,
+    ctrl_alg: CompressionAlgorithm,
+    diff_alg: CompressionAlgorithm,
+    extra_alg: CompressionAlgorithm,
+    options: &DiffOptions,
+) -> io::Result<()> {
+    DiffIndex::new_with_options(old, options)?
+        .diff_bsdf2_with_options(new, writer, ctrl_alg, diff_alg, extra_alg, options)
+}
+
+/// Generate a BSDF2 patch, choosing the smallest codec from `allowed` for each stream
+pub fn diff_bsdf2_auto<T: Write>(
+    old: &[u8],
+    new: &[u8],
+    writer: &mut T,
+    allowed: &[CompressionAlgorithm],
+) -> io::Result<()> {
+    diff_bsdf2_auto_with_options(old, new, writer, allowed, &DiffOptions::default())
+}
+
+/// Generate a BSDF2 patch with automatic codec selection and custom scan options
+pub fn diff_bsdf2_auto_with_options<T: Write>(
+    old: &[u8],
+    new: &[u8],
+    writer: &mut T,
+    allowed: &[CompressionAlgorithm],
+    options: &DiffOptions,
+) -> io::Result<()> {
+    DiffIndex::new_with_options(old, options)?
+        .diff_bsdf2_auto_with_options(new, writer, allowed, options)
+}
+
+/// Run the diff and send its output to a custom `ControlSink`
+pub fn diff_to_sink<S: ControlSink + ?Sized>(
+    old: &[u8],
+    new: &[u8],
+    sink: &mut S,
+    options: &DiffOptions,
+) -> io::Result<()> {
+    DiffIndex::new_with_options(old, options)?.diff_to_sink(new, sink, options)
 }
 
 /// Generate a BSDF2 patch with all streams using the same compression
@@ -73,381 +218,287 @@ pub fn diff_bsdf2_uniform<T: Write>(
     diff_bsdf2(old, new, writer, alg, alg, alg)
 }
 
-#[inline(always)]
-fn usz(i: isize) -> usize {
-    debug_assert!(i >= 0);
-    i as usize
+/// The suffix array of an "old" file, built once and reused for many diffs.
+///
+/// Building the index is the expensive part of diffing. The index is `Send + Sync`,
+/// so several threads can generate patches against the same old file at once.
+///
+/// ```rust,ignore
+/// let index = DiffIndex::new(&base);
+/// for variant in &variants {
+///     let mut patch = Vec::new();
+///     index.diff_bsdf2_uniform(variant, &mut patch, CompressionAlgorithm::Brotli)?;
+/// }
+/// ```
+pub struct DiffIndex<'a> {
+    old: &'a [u8],
+    sa: SuffixArray,
 }
 
-struct SplitParams {
-    start: usize,
-    len: usize,
-}
-
-#[inline]
-fn split_internal(
-    I: &mut [isize],
-    V: &mut [isize],
-    start: usize,
-    len: usize,
-    h: usize,
-) -> Option<SplitParams> {
-    if len < 16 {
-        let mut k = start;
-        while k < start + len {
-            let mut j = 1;
-            let mut x = V[usz(I[k] + h as isize)];
-            let mut i = 1;
-            while k + i < start + len {
-                let v = V[usz(I[k + i] + h as isize)];
-                if v < x {
-                    x = v;
-                    j = 0;
-                }
-                if v == x {
-                    I.swap(k + j, k + i);
-                    j += 1;
-                }
-                i += 1;
-            }
-            let kj = (k + j) as isize;
-            for &Ii in &I[k..k + j] {
-                V[usz(Ii)] = kj - 1;
-            }
-            if j == 1 {
-                I[k] = -1;
-            }
-            k += j;
-        }
-        None
-    } else {
-        let x = V[usz(I[start + len / 2] + h as isize)];
-        
-        let mut jj = 0;
-        let mut kk = 0;
-        for &Ii in &I[start..start + len] {
-            let v = V[usz(Ii + h as isize)];
-            if v < x {
-                jj += 1;
-            }
-            if v == x {
-                kk += 1;
-            }
-        }
-        let jj = jj + start;
-        let kk = kk + jj;
-        
-        let mut j = 0;
-        let mut k = 0;
-        let mut i = start;
-        while i < jj {
-            match V[usz(I[i] + h as isize)].cmp(&x) {
-                Ordering::Less => i += 1,
-                Ordering::Equal => {
-                    I.swap(i, jj + j);
-                    j += 1;
-                }
-                Ordering::Greater => {
-                    I.swap(i, kk + k);
-                    k += 1;
-                }
-            }
-        }
-        
-        while jj + j < kk {
-            if V[usz(I[jj + j] + h as isize)] == x {
-                j += 1;
-            } else {
-                I.swap(jj + j, kk + k);
-                k += 1;
-            }
-        }
-        
-        if jj > start {
-            split(I, V, start, jj - start, h);
-        }
-        
-        let kk_minus_1 = (kk - 1) as isize;
-        for &Ii in &I[jj..kk] {
-            V[usz(Ii)] = kk_minus_1;
-        }
-        if jj == kk - 1 {
-            I[jj] = -1;
+impl<'a> DiffIndex<'a> {
+    /// Sort the suffixes of `old`
+    pub fn new(old: &'a [u8]) -> Self {
+        let mut reporter = Reporter::new(None, None, Phase::SuffixSort, old.len() as u64);
+        Self {
+            old,
+            sa: SuffixArray::new(old, &mut reporter).expect("sorting without a cancel token"),
         }
+    }
 
-        if start + len > kk {
-            Some(SplitParams {
-                start: kk,
-                len: start + len - kk,
-            })
-        } else {
-            None
-        }
+    /// Sort the suffixes of `old`, reporting `Phase::SuffixSort` progress after each
+    /// pass of the sort and stopping at the end of the current pass if
+    /// `options.cancel` is triggered
+    pub fn new_with_options(old: &'a [u8], options: &DiffOptions) -> io::Result<Self> {
+        let mut reporter = Reporter::new(
+            options.progress.as_ref(),
+            options.cancel.as_ref(),
+            Phase::SuffixSort,
+            old.len() as u64,
+        );
+        reporter.check()?;
+        reporter.report(0);
+        let index = Self {
+            old,
+            sa: SuffixArray::new(old, &mut reporter)?,
+        };
+        reporter.report(old.len() as u64);
+        Ok(index)
     }
-}
 
-fn split(I: &mut [isize], V: &mut [isize], start: usize, len: usize, h: usize) {
-    let mut ret = Some(SplitParams { start, len });
-    while let Some(params) = ret {
-        ret = split_internal(I, V, params.start, params.len, h);
+    /// Map an index previously written by `save`.
+    ///
+    /// Fails with `InvalidData` if the index was built from different `old` bytes,
+    /// which is checked against the length and content hash stored in the file, or
+    /// if any of its entries points past the end of `old`.
+    pub fn load<P: AsRef<Path>>(old: &'a [u8], path: P) -> io::Result<Self> {
+        Ok(Self {
+            old,
+            sa: SuffixArray::map_file(old, path.as_ref())?,
+        })
     }
-}
 
-fn qsufsort(I: &mut [isize], V: &mut [isize], old: &[u8]) {
-    let mut buckets: [isize; 256] = [0; 256];
-    
-    for &o in old {
-        buckets[o as usize] += 1;
-    }
-    
-    for i in 1..256 {
-        buckets[i] += buckets[i - 1];
-    }
-    
-    for i in (1..256).rev() {
-        buckets[i] = buckets[i - 1];
-    }
-    buckets[0] = 0;
-    
-    for (i, &old_byte) in old.iter().enumerate() {
-        buckets[old_byte as usize] += 1;
-        I[usz(buckets[old_byte as usize])] = i as isize;
-    }
-    
-    I[0] = old.len() as isize;
-    
-    for (i, &old_byte) in old.iter().enumerate() {
-        V[i] = buckets[old_byte as usize];
-    }
-    V[old.len()] = 0;
-    
-    for i in 1..256 {
-        if buckets[i] == buckets[i - 1] + 1 {
-            I[usz(buckets[i])] = -1;
-        }
+    /// Write the suffix array to `path` so later runs can `load` it instead of sorting again
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_to(&mut file)?;
+        file.into_inner()?.sync_all()
     }
-    I[0] = -1;
-    
-    let mut h = 1;
-    while I[0] != -(old.len() as isize + 1) {
-        let mut len = 0;
-        let mut i = 0;
-        while i < old.len() as isize + 1 {
-            if I[usz(i)] < 0 {
-                len -= I[usz(i)];
-                i = i - I[usz(i)];
-            } else {
-                if len != 0 {
-                    I[usz(i - len)] = -len;
-                }
-                len = V[usz(I[usz(i)])] + 1 - i;
-                split(I, V, usz(i), usz(len), h);
-                i += len;
-                len = 0;
-            }
-        }
-        if len != 0 {
-            I[usz(i - len)] = -len;
-        }
-        h += h;
+
+    /// Serialize the suffix array in the format read by `load`
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.sa.write_to(self.old, writer)
     }
-    
-    for (i, &v) in V[0..=old.len()].iter().enumerate() {
-        I[usz(v)] = i as isize;
+
+    /// The old file this index was built from
+    pub fn old(&self) -> &'a [u8] {
+        self.old
     }
-}
 
-#[inline]
-fn matchlen(old: &[u8], new: &[u8]) -> usize {
-    old.iter()
-        .zip(new)
-        .take_while(|(a, b)| a == b)
-        .count()
-}
+    /// Run the diff and send its output to a custom `ControlSink`, like `diff_to_sink()`
+    pub fn diff_to_sink<S: ControlSink + ?Sized>(
+        &self,
+        new: &[u8],
+        sink: &mut S,
+        options: &DiffOptions,
+    ) -> io::Result<()> {
+        let scratch_limit = match options.in_place_scratch {
+            Some(limit) => limit,
+            None => return bsdiff_scan(&self.sa, self.old, new, sink, options),
+        };
 
-fn search(I: &[isize], old: &[u8], new: &[u8]) -> (isize, usize) {
-    if I.len() < 3 {
-        let x = matchlen(&old[usz(I[0])..], new);
-        let y = matchlen(&old[usz(I[I.len() - 1])..], new);
-        if x > y {
-            (I[0], x)
-        } else {
-            (I[I.len() - 1], y)
-        }
-    } else {
-        let mid = (I.len() - 1) / 2;
-        let left = &old[usz(I[mid])..];
-        let right = new;
-        let len_to_check = left.len().min(right.len());
-        
-        if left[..len_to_check] < right[..len_to_check] {
-            search(&I[mid..], old, new)
-        } else {
-            search(&I[..=mid], old, new)
-        }
+        // Reordering needs every control entry up front; the data is rebuilt from them
+        let mut entries = EntryRecorder(Vec::new());
+        bsdiff_scan(&self.sa, self.old, new, &mut entries, options)?;
+        make_in_place_safe(&mut entries.0, self.old.len(), scratch_limit);
+        replay(&entries.0, self.old, new, sink)
+    }
+
+    /// Generate a raw patch, like `diff()`
+    pub fn diff<T: Write>(&self, new: &[u8], writer: &mut T) -> io::Result<()> {
+        self.diff_with_options(new, writer, &DiffOptions::default())
+    }
+
+    /// Generate a raw patch with custom scan options
+    pub fn diff_with_options<T: Write>(
+        &self,
+        new: &[u8],
+        writer: &mut T,
+        options: &DiffOptions,
+    ) -> io::Result<()> {
+        self.diff_to_sink(new, &mut RawWriter::new(writer), options)
+    }
+
+    /// Generate a legacy BSDIFF40 patch, like `diff_bsdiff40()`
+    pub fn diff_bsdiff40<T: Write>(&self, new: &[u8], writer: &mut T) -> io::Result<()> {
+        self.diff_bsdiff40_with_options(new, writer, &DiffOptions::default())
+    }
+
+    /// Generate a legacy BSDIFF40 patch with custom scan options
+    pub fn diff_bsdiff40_with_options<T: Write>(
+        &self,
+        new: &[u8],
+        writer: &mut T,
+        options: &DiffOptions,
+    ) -> io::Result<()> {
+        let mut patch_writer = options.writer_params(Bsdf2Writer::new_legacy());
+        self.diff_to_sink(new, &mut patch_writer, options)?;
+        patch_writer.close(writer)
     }
-}
 
-/// Encode signed integer in bspatch sign-magnitude format
-#[inline]
-fn offtout(x: isize, buf: &mut [u8]) {
-    let x64 = x as i64;
-    if x64 >= 0 {
-        buf.copy_from_slice(&x64.to_le_bytes());
-    } else {
-        let tmp = (-x64) as u64 | (1u64 << 63);
-        buf.copy_from_slice(&tmp.to_le_bytes());
+    /// Generate an `ENDSLEY/BSDIFF43` patch, like `diff_endsley()`
+    pub fn diff_endsley<T: Write>(&self, new: &[u8], writer: &mut T) -> io::Result<()> {
+        self.diff_endsley_with_options(new, writer, &DiffOptions::default())
+    }
+
+    /// Generate an `ENDSLEY/BSDIFF43` patch with custom scan options
+    pub fn diff_endsley_with_options<T: Write>(
+        &self,
+        new: &[u8],
+        writer: &mut T,
+        options: &DiffOptions,
+    ) -> io::Result<()> {
+        let mut patch_writer = EndsleyWriter::new().with_params(options.ctrl_params);
+        self.diff_to_sink(new, &mut patch_writer, options)?;
+        patch_writer.close(writer)
+    }
+
+    /// Generate a BSDF2 patch, like `diff_bsdf2()`
+    pub fn diff_bsdf2<T: Write>(
+        &self,
+        new: &[u8],
+        writer: &mut T,
+        ctrl_alg: CompressionAlgorithm,
+        diff_alg: CompressionAlgorithm,
+        extra_alg: CompressionAlgorithm,
+    ) -> io::Result<()> {
+        self.diff_bsdf2_with_options(
+            new,
+            writer,
+            ctrl_alg,
+            diff_alg,
+            extra_alg,
+            &DiffOptions::default(),
+        )
+    }
+
+    /// Generate a BSDF2 patch with custom scan options
+    pub fn diff_bsdf2_with_options<T: Write>(
+        &self,
+        new: &[u8],
+        writer: &mut T,
+        ctrl_alg: CompressionAlgorithm,
+        diff_alg: CompressionAlgorithm,
+        extra_alg: CompressionAlgorithm,
+        options: &DiffOptions,
+    ) -> io::Result<()> {
+        let mut patch_writer =
+            options.writer_params(Bsdf2Writer::new(ctrl_alg, diff_alg, extra_alg));
+        self.diff_to_sink(new, &mut patch_writer, options)?;
+        patch_writer.close(writer)
+    }
+
+    /// Generate a BSDF2 patch with automatic codec selection, like `diff_bsdf2_auto()`
+    pub fn diff_bsdf2_auto<T: Write>(
+        &self,
+        new: &[u8],
+        writer: &mut T,
+        allowed: &[CompressionAlgorithm],
+    ) -> io::Result<()> {
+        self.diff_bsdf2_auto_with_options(new, writer, allowed, &DiffOptions::default())
+    }
+
+    /// Generate a BSDF2 patch with automatic codec selection and custom scan options
+    pub fn diff_bsdf2_auto_with_options<T: Write>(
+        &self,
+        new: &[u8],
+        writer: &mut T,
+        allowed: &[CompressionAlgorithm],
+        options: &DiffOptions,
+    ) -> io::Result<()> {
+        let mut patch_writer = options.writer_params(Bsdf2Writer::new_auto(allowed));
+        self.diff_to_sink(new, &mut patch_writer, options)?;
+        patch_writer.close(writer)
+    }
+
+    /// Generate a BSDF2 patch with one codec for all streams, like `diff_bsdf2_uniform()`
+    pub fn diff_bsdf2_uniform<T: Write>(
+        &self,
+        new: &[u8],
+        writer: &mut T,
+        alg: CompressionAlgorithm,
+    ) -> io::Result<()> {
+        self.diff_bsdf2(new, writer, alg, alg, alg)
     }
 }
 
-/// Original bsdiff algorithm that writes directly (for backward compatibility)
-fn bsdiff_internal(old: &[u8], new: &[u8], writer: &mut dyn Write) -> io::Result<()> {
-    let mut I = vec![0; old.len() + 1];
-    let mut V = vec![0; old.len() + 1];
-    
-    qsufsort(&mut I, &mut V, old);
+/// Keeps the control entries of a scan and drops its data
+struct EntryRecorder(Vec<ControlEntry>);
 
-    let mut buffer = Vec::with_capacity(1024);
+impl ControlSink for EntryRecorder {
+    fn control(&mut self, entry: ControlEntry) -> io::Result<()> {
+        self.0.push(entry);
+        Ok(())
+    }
 
-    let mut scan = 0;
-    let mut len = 0usize;
-    let mut pos = 0usize;
-    let mut lastscan = 0;
-    let mut lastpos = 0;
-    let mut lastoffset = 0isize;
-    
-    while scan < new.len() {
-        let mut oldscore = 0;
-        scan += len;
-        let mut scsc = scan;
-        
-        while scan < new.len() {
-            let (p, l) = search(&I[..=old.len()], old, &new[scan..]);
-            pos = usz(p);
-            len = l;
-            
-            while scsc < scan + len {
-                if scsc as isize + lastoffset < old.len() as _
-                    && (old[usz(scsc as isize + lastoffset)] == new[scsc])
-                {
-                    oldscore += 1;
-                }
-                scsc += 1;
-            }
-            
-            if len == oldscore && (len != 0) || len > oldscore + 8 {
-                break;
-            }
-            
-            if scan as isize + lastoffset < old.len() as _
-                && (old[usz(scan as isize + lastoffset)] == new[scan])
-            {
-                oldscore -= 1;
-            }
-            scan += 1;
-        }
-        
-        if !(len != oldscore || scan == new.len()) {
-            continue;
-        }
-        
-        let mut s = 0;
-        let mut Sf = 0;
-        let mut lenf = 0usize;
-        let mut i = 0usize;
-        while lastscan + i < scan && (lastpos + i < old.len() as _) {
-            if old[lastpos + i] == new[lastscan + i] {
-                s += 1;
-            }
-            i += 1;
-            if s * 2 - i as isize <= Sf * 2 - lenf as isize {
-                continue;
-            }
-            Sf = s;
-            lenf = i;
-        }
-        
-        let mut lenb = 0;
-        if scan < new.len() {
-            let mut s = 0isize;
-            let mut Sb = 0;
-            let mut i = 1;
-            while scan >= lastscan + i && (pos >= i) {
-                if old[pos - i] == new[scan - i] {
-                    s += 1;
-                }
-                if s * 2 - i as isize > Sb * 2 - lenb as isize {
-                    Sb = s;
-                    lenb = i;
-                }
-                i += 1;
-            }
-        }
-        
-        if lastscan + lenf > scan - lenb {
-            let overlap = lastscan + lenf - (scan - lenb);
-            let mut s = 0;
-            let mut Ss = 0;
-            let mut lens = 0;
-            for i in 0..overlap {
-                if new[lastscan + lenf - overlap + i] == old[lastpos + lenf - overlap + i] {
-                    s += 1;
-                }
-                if new[scan - lenb + i] == old[pos - lenb + i] {
-                    s -= 1;
-                }
-                if s > Ss {
-                    Ss = s;
-                    lens = i + 1;
-                }
-            }
-            lenf = lenf + lens - overlap;
-            lenb -= lens;
-        }
-        
-        // Write control tuple (original format)
-        let mut buf: [u8; 24] = [0; 24];
-        offtout(lenf as _, &mut buf[..8]);
-        offtout(
-            scan as isize - lenb as isize - (lastscan + lenf) as isize,
-            &mut buf[8..16],
-        );
-        offtout(
-            pos as isize - lenb as isize - (lastpos + lenf) as isize,
-            &mut buf[16..24],
-        );
-        writer.write_all(&buf[..24])?;
+    fn diff(&mut self, _bytes: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn extra(&mut self, _bytes: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Send `entries` to `sink` with their diff and extra data taken from old and new
+fn replay<S: ControlSink + ?Sized>(
+    entries: &[ControlEntry],
+    old: &[u8],
+    new: &[u8],
+    sink: &mut S,
+) -> io::Result<()> {
+    let mut buffer = Vec::with_capacity(1024);
+    let (mut oldpos, mut newpos) = (0i64, 0usize);
+    for entry in entries {
+        let (lenf, extra_len) = (entry.diff_size as usize, entry.extra_size as usize);
+        let lastpos = usz(oldpos as isize);
+        sink.control(*entry)?;
 
-        // Write diff data
         buffer.clear();
         buffer.extend(
-            new[lastscan..lastscan + lenf]
+            new[newpos..newpos + lenf]
                 .iter()
                 .zip(&old[lastpos..lastpos + lenf])
                 .map(|(n, o)| n.wrapping_sub(*o)),
         );
-        writer.write_all(&buffer)?;
-
-        // Write extra data
-        let write_len = scan - lenb - (lastscan + lenf);
-        let write_start = lastscan + lenf;
-        writer.write_all(&new[write_start..write_start + write_len])?;
+        sink.diff(&buffer)?;
+        sink.extra(&new[newpos + lenf..newpos + lenf + extra_len])?;
 
-        lastscan = scan - lenb;
-        lastpos = pos - lenb;
-        lastoffset = pos as isize - scan as isize;
+        newpos += lenf + extra_len;
+        oldpos += entry.diff_size + entry.offset_increment;
     }
-
     Ok(())
 }
 
-/// Bsdiff algorithm using Bsdf2Writer (for BSDF2 format)
-fn bsdiff_with_writer(old: &[u8], new: &[u8], writer: &mut Bsdf2Writer) -> io::Result<()> {
-    let mut I = vec![0; old.len() + 1];
-    let mut V = vec![0; old.len() + 1];
-    
-    qsufsort(&mut I, &mut V, old);
+#[inline(always)]
+fn usz(i: isize) -> usize {
+    debug_assert!(i >= 0);
+    i as usize
+}
 
+/// The bsdiff scan loop, emitting control entries and data to `sink`
+fn bsdiff_scan<S: ControlSink + ?Sized>(
+    sa: &SuffixArray,
+    old: &[u8],
+    new: &[u8],
+    sink: &mut S,
+    options: &DiffOptions,
+) -> io::Result<()> {
+    let mut reporter = Reporter::new(
+        options.progress.as_ref(),
+        options.cancel.as_ref(),
+        Phase::Scan,
+        new.len() as u64,
+    );
     let mut buffer = Vec::with_capacity(1024);
 
     let mut scan = 0;
@@ -456,17 +507,18 @@ fn bsdiff_with_writer(old: &[u8], new: &[u8], writer: &mut Bsdf2Writer) -> io::R
     let mut lastscan = 0;
     let mut lastpos = 0;
     let mut lastoffset = 0isize;
-    
+
     while scan < new.len() {
+        reporter.update(scan as u64)?;
         let mut oldscore = 0;
         scan += len;
         let mut scsc = scan;
-        
+
         while scan < new.len() {
-            let (p, l) = search(&I[..=old.len()], old, &new[scan..]);
-            pos = usz(p);
+            let (p, l) = sa.search(old, &new[scan..]);
+            pos = p;
             len = l;
-            
+
             while scsc < scan + len {
                 if scsc as isize + lastoffset < old.len() as _
                     && (old[usz(scsc as isize + lastoffset)] == new[scsc])
@@ -475,11 +527,13 @@ fn bsdiff_with_writer(old: &[u8], new: &[u8], writer: &mut Bsdf2Writer) -> io::R
                 }
                 scsc += 1;
             }
-            
-            if len == oldscore && (len != 0) || len > oldscore + 8 {
+
+            if len == oldscore && (len != 0)
+                || len > oldscore + options.mismatch_threshold && len >= options.min_match_len
+            {
                 break;
             }
-            
+
             if scan as isize + lastoffset < old.len() as _
                 && (old[usz(scan as isize + lastoffset)] == new[scan])
             {
@@ -487,11 +541,11 @@ fn bsdiff_with_writer(old: &[u8], new: &[u8], writer: &mut Bsdf2Writer) -> io::R
             }
             scan += 1;
         }
-        
+
         if !(len != oldscore || scan == new.len()) {
             continue;
         }
-        
+
         let mut s = 0;
         let mut Sf = 0;
         let mut lenf = 0usize;
@@ -507,9 +561,9 @@ fn bsdiff_with_writer(old: &[u8], new: &[u8], writer: &mut Bsdf2Writer) -> io::R
             Sf = s;
             lenf = i;
         }
-        
+
         let mut lenb = 0;
-        if scan < new.len() {
+        if scan < new.len() && options.extend_backward {
             let mut s = 0isize;
             let mut Sb = 0;
             let mut i = 1;
@@ -524,7 +578,7 @@ fn bsdiff_with_writer(old: &[u8], new: &[u8], writer: &mut Bsdf2Writer) -> io::R
                 i += 1;
             }
         }
-        
+
         if lastscan + lenf > scan - lenb {
             let overlap = lastscan + lenf - (scan - lenb);
             let mut s = 0;
@@ -545,14 +599,14 @@ fn bsdiff_with_writer(old: &[u8], new: &[u8], writer: &mut Bsdf2Writer) -> io::R
             lenf = lenf + lens - overlap;
             lenb -= lens;
         }
-        
+
         // Add control entry
         let entry = ControlEntry {
             diff_size: lenf as i64,
             extra_size: (scan as isize - lenb as isize - (lastscan + lenf) as isize) as i64,
             offset_increment: (pos as isize - lenb as isize - (lastpos + lenf) as isize) as i64,
         };
-        writer.add_control_entry(entry)?;
+        sink.control(entry)?;
 
         // Write diff data
         buffer.clear();
@@ -562,17 +616,327 @@ fn bsdiff_with_writer(old: &[u8], new: &[u8], writer: &mut Bsdf2Writer) -> io::R
                 .zip(&old[lastpos..lastpos + lenf])
                 .map(|(n, o)| n.wrapping_sub(*o)),
         );
-        writer.write_diff_stream(&buffer)?;
+        sink.diff(&buffer)?;
 
         // Write extra data
         let write_len = scan - lenb - (lastscan + lenf);
         let write_start = lastscan + lenf;
-        writer.write_extra_stream(&new[write_start..write_start + write_len])?;
+        sink.extra(&new[write_start..write_start + write_len])?;
 
         lastscan = scan - lenb;
         lastpos = pos - lenb;
         lastoffset = pos as isize - scan as isize;
     }
 
+    reporter.report(new.len() as u64);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<DiffIndex<'static>>();
+    }
+
+    #[test]
+    fn test_options_roundtrip() {
+        let mut seed = 7u32;
+        let old: Vec<u8> = (0..8000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        // Blocks of old of mixed lengths, moved around and lightly edited
+        let mut new = Vec::new();
+        for (i, len) in [40usize, 300, 12, 900, 60, 25, 500, 150].iter().enumerate() {
+            let start = (i * 977) % (old.len() - len);
+            new.extend_from_slice(&old[start..start + len]);
+            new.push(i as u8);
+        }
+        for i in (0..new.len()).step_by(53) {
+            new[i] ^= 0x55;
+        }
+
+        let mut default_patch = Vec::new();
+        diff(&old, &new, &mut default_patch).unwrap();
+        let mut same = Vec::new();
+        diff_with_options(&old, &new, &mut same, &DiffOptions::default()).unwrap();
+        assert_eq!(default_patch, same);
+
+        let tuned = [
+            DiffOptions {
+                min_match_len: 200,
+                ..DiffOptions::default()
+            },
+            DiffOptions {
+                mismatch_threshold: 32,
+                ..DiffOptions::default()
+            },
+            DiffOptions {
+                extend_backward: false,
+                ..DiffOptions::default()
+            },
+        ];
+        let entries = |options: &DiffOptions| {
+            let mut recorder = EntryRecorder(Vec::new());
+            diff_to_sink(&old, &new, &mut recorder, options).unwrap();
+            recorder.0
+        };
+        let default_entries = entries(&DiffOptions::default());
+        for options in &tuned {
+            let mut patch = Vec::new();
+            diff_bsdiff40_with_options(&old, &new, &mut patch, options).unwrap();
+            let mut out = Vec::new();
+            crate::patch_bsdf2(&old, &patch, &mut out).unwrap();
+            assert_eq!(out, new);
+
+            // Every knob must change how new is split into matches
+            assert_ne!(entries(options), default_entries, "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn test_compression_params_are_used() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i % 7) as u8).collect();
+        let new: Vec<u8> = (0..20_000u32).map(|i| (i * i % 251) as u8).collect();
+
+        let mut best = Vec::new();
+        diff_bsdf2_uniform(&old, &new, &mut best, CompressionAlgorithm::Brotli).unwrap();
+
+        let fast = CompressionParams {
+            brotli_quality: 0,
+            ..CompressionParams::default()
+        };
+        let options = DiffOptions {
+            ctrl_params: fast,
+            diff_params: fast,
+            extra_params: fast,
+            ..DiffOptions::default()
+        };
+        let mut quick = Vec::new();
+        let alg = CompressionAlgorithm::Brotli;
+        diff_bsdf2_with_options(&old, &new, &mut quick, alg, alg, alg, &options).unwrap();
+        assert_ne!(best, quick);
+
+        let mut out = Vec::new();
+        crate::patch_bsdf2(&old, &quick, &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn test_in_place_safe_patches() {
+        use std::io::Cursor;
+
+        // Swapping two halves makes every match read what another one writes
+        let old: Vec<u8> = (0..60_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old[30_000..].to_vec();
+        new.extend_from_slice(&old[..30_000]);
+        new[1234] ^= 0x55;
+
+        let alg = CompressionAlgorithm::Bz2;
+        let mut plain = Vec::new();
+        diff_bsdf2_uniform(&old, &new, &mut plain, alg).unwrap();
+        let mut target = Cursor::new(old.clone());
+        let err = crate::patch_bsdf2_in_place(&mut target, &plain, &mut Cursor::new(Vec::new()), 0);
+        assert!(err.is_err());
+
+        for scratch in [0, 10_000, 1 << 20] {
+            let options = DiffOptions {
+                in_place_scratch: Some(scratch),
+                ..DiffOptions::default()
+            };
+            let mut patch = Vec::new();
+            diff_bsdf2_with_options(&old, &new, &mut patch, alg, alg, alg, &options).unwrap();
+
+            // With enough scratch nothing needs converting
+            assert_eq!(patch == plain, scratch == 1 << 20);
+            assert!(patch.len() >= plain.len());
+
+            let mut out = Vec::new();
+            crate::patch_bsdf2(&old, &patch, &mut out).unwrap();
+            assert_eq!(out, new);
+
+            let mut target = Cursor::new(old.clone());
+            let mut journal = Cursor::new(Vec::new());
+            crate::patch_bsdf2_in_place(&mut target, &patch, &mut journal, scratch).unwrap();
+            assert!(target.into_inner() == new, "scratch {}", scratch);
+        }
+    }
+
+    #[test]
+    fn test_auto_codecs_pick_smallest() {
+        use CompressionAlgorithm::{Brotli, Bz2, None};
+
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i % 101) as u8).collect();
+        let mut new = old.clone();
+        new[5000..5400]
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = (i * i) as u8);
+
+        let mut auto = Vec::new();
+        diff_bsdf2_auto(&old, &new, &mut auto, &[None, Bz2, Brotli]).unwrap();
+        for alg in [None, Bz2, Brotli] {
+            let mut uniform = Vec::new();
+            diff_bsdf2_uniform(&old, &new, &mut uniform, alg).unwrap();
+            assert!(auto.len() <= uniform.len());
+        }
+        let mut out = Vec::new();
+        crate::patch_bsdf2(&old, &auto, &mut out).unwrap();
+        assert_eq!(out, new);
+
+        let mut legacy = Vec::new();
+        diff_bsdf2_auto(&old, &new, &mut legacy, &[Bz2]).unwrap();
+        assert!(legacy.starts_with(b"BSDIFF40"));
+
+        // The control stream is too small for bzip2 to pay off, so the mix needs BSDF2
+        let mut restricted = Vec::new();
+        diff_bsdf2_auto(&old, &new, &mut restricted, &[None, Bz2]).unwrap();
+        let header = crate::Bsdf2Header::parse(&restricted).unwrap();
+        assert_eq!(header.format, crate::PatchFormat::Bsdf2);
+        for alg in [header.ctrl_alg, header.diff_alg, header.extra_alg] {
+            assert!(matches!(alg, None | Bz2), "{:?} was not allowed", alg);
+        }
+        for alg in [None, Bz2] {
+            let mut uniform = Vec::new();
+            diff_bsdf2_uniform(&old, &new, &mut uniform, alg).unwrap();
+            assert!(restricted.len() <= uniform.len());
+        }
+    }
+
+    #[test]
+    fn test_diff_progress_and_cancel() {
+        use crate::progress::{is_cancelled, Progress};
+        use std::sync::{Arc, Mutex};
+
+        let old: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 253) as u8).collect();
+        let new: Vec<u8> = old.iter().rev().copied().collect();
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        let options = DiffOptions {
+            progress: Some(ProgressCallback::new(move |p: Progress| {
+                sink.lock().unwrap().push(p)
+            })),
+            ..DiffOptions::default()
+        };
+        let mut patch = Vec::new();
+        diff_with_options(&old, &new, &mut patch, &options).unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.first().unwrap().phase, Phase::SuffixSort);
+        assert!(seen
+            .iter()
+            .any(|p| p.phase == Phase::SuffixSort && p.done > 0 && p.done < p.total));
+        let last = seen.last().unwrap();
+        assert_eq!(
+            (last.phase, last.done, last.total),
+            (Phase::Scan, 100_000, 100_000)
+        );
+        assert!(seen
+            .windows(2)
+            .all(|w| w[0].phase != w[1].phase || w[0].done <= w[1].done));
+
+        let token = CancelToken::new();
+        token.cancel();
+        let options = DiffOptions {
+            cancel: Some(token),
+            ..DiffOptions::default()
+        };
+        let err = diff_with_options(&old, &new, &mut Vec::new(), &options).unwrap_err();
+        assert!(is_cancelled(&err));
+
+        // Cancelling partway through the sort stops it before the scan starts
+        let token = CancelToken::new();
+        let cancel = token.clone();
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&phases);
+        let options = DiffOptions {
+            progress: Some(ProgressCallback::new(move |p: Progress| {
+                sink.lock().unwrap().push(p.phase);
+                if p.done > 0 {
+                    cancel.cancel();
+                }
+            })),
+            cancel: Some(token),
+            ..DiffOptions::default()
+        };
+        let err = diff_with_options(&old, &new, &mut Vec::new(), &options).unwrap_err();
+        assert!(is_cancelled(&err));
+        assert!(phases
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|&p| p == Phase::SuffixSort));
+    }
+
+    #[test]
+    fn test_index_save_and_load() {
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 256) as u8).collect();
+        let mut new = old.clone();
+        new[100..200].fill(9);
+
+        let path = std::env::temp_dir().join(format!("bsdiff-index-{}.idx", std::process::id()));
+        DiffIndex::new(&old).save(&path).unwrap();
+
+        let mut expected = Vec::new();
+        diff(&old, &new, &mut expected).unwrap();
+        let mut patch = Vec::new();
+        DiffIndex::load(&old, &path)
+            .unwrap()
+            .diff(&new, &mut patch)
+            .unwrap();
+        assert_eq!(patch, expected);
+
+        let err = DiffIndex::load(&new, &path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // An entry pointing past the end of old is caught on load, not in `search`
+        let mut saved = std::fs::read(&path).unwrap();
+        saved[32 + 4 * 100..32 + 4 * 101].copy_from_slice(&6000u32.to_le_bytes());
+        std::fs::write(&path, &saved).unwrap();
+        let err = DiffIndex::load(&old, &path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_index_reused_across_threads() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * 13 % 256) as u8).collect();
+        let variants: Vec<Vec<u8>> = (0..4u8)
+            .map(|v| {
+                let mut new = old.clone();
+                new[v as usize * 1000..v as usize * 1000 + 50].fill(v);
+                new
+            })
+            .collect();
+
+        let index = DiffIndex::new(&old);
+        let patches: Vec<Vec<u8>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = variants
+                .iter()
+                .map(|new| {
+                    let index = &index;
+                    scope.spawn(move || {
+                        let mut patch = Vec::new();
+                        index.diff_bsdiff40(new, &mut patch).unwrap();
+                        patch
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        for (new, patch) in variants.iter().zip(&patches) {
+            let mut expected = Vec::new();
+            diff_bsdiff40(&old, new, &mut expected).unwrap();
+            assert_eq!(patch, &expected);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+// endsley.rs - ENDSLEY/BSDIFF43 format from Matthew Endsley's bsdiff
+
+use std::io::{self, Write};
+
+use crate::bsdf2::offtin;
+use crate::bsdf2_writer::ControlEntry;
+use crate::bsdf2_writer::{compress, encode_int64, CompressionAlgorithm, CompressionParams};
+use crate::error::{Limit, PatchError, Position};
+use crate::patch::{try_patch, try_reserve, PatchLimits, PatchOptions};
+use crate::sink::{ControlSink, RawWriter};
+
+pub(crate) const ENDSLEY_MAGIC: &[u8; 16] = b"ENDSLEY/BSDIFF43";
+
+/// `ENDSLEY/BSDIFF43` patch writer.
+///
+/// The format has a single bzip2 stream in which each control entry is immediately
+/// followed by its diff and extra data, so they must be written in that order, as
+/// `ControlSink` delivers them. Nothing is written to the output until `close`,
+/// because the header records the size of the new file.
+pub struct EndsleyWriter {
+    stream: RawWriter<Vec<u8>>,
+    params: CompressionParams,
+    written_output: u64,
+}
+
+impl EndsleyWriter {
+    pub fn new() -> Self {
+        Self {
+            stream: RawWriter::new(Vec::new()),
+            params: CompressionParams::default(),
+            written_output: 0,
+        }
+    }
+
+    /// Set the bzip2 parameters of the stream
+    pub fn with_params(mut self, params: CompressionParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Write the magic, the new size and the compressed stream
+    pub fn close<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let data = std::mem::take(self.stream.get_mut());
+        let compressed = compress(CompressionAlgorithm::Bz2, &self.params, &data)?;
+
+        let mut header = [0u8; 24];
+        header[0..16].copy_from_slice(ENDSLEY_MAGIC);
+        encode_int64(self.written_output as i64, &mut header[16..24]);
+
+        writer.write_all(&header)?;
+        writer.write_all(&compressed)?;
+        Ok(())
+    }
+}
+
+impl Default for EndsleyWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlSink for EndsleyWriter {
+    fn control(&mut self, entry: ControlEntry) -> io::Result<()> {
+        self.stream.control(entry)
+    }
+
+    fn diff(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.written_output += bytes.len() as u64;
+        self.stream.diff(bytes)
+    }
+
+    fn extra(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.written_output += bytes.len() as u64;
+        self.stream.extra(bytes)
+    }
+}
+
+/// Apply an `ENDSLEY/BSDIFF43` patch.
+///
+/// The format is a 16-byte magic and the new size, followed by one bzip2 stream
+/// holding control tuples, diff and extra data interleaved the same way as a raw
+/// patch. `new` is cleared before the patched file is written into it.
+pub fn patch_endsley(old: &[u8], patch_data: &[u8], new: &mut Vec<u8>) -> io::Result<()> {
+    patch_endsley_with_options(old, patch_data, new, &PatchOptions::default())
+}
+
+/// Apply an `ENDSLEY/BSDIFF43` patch with limits, progress reporting and cancellation
+pub fn patch_endsley_with_options(
+    old: &[u8],
+    patch_data: &[u8],
+    new: &mut Vec<u8>,
+    options: &PatchOptions,
+) -> io::Result<()> {
+    if patch_data.len() < 24 {
+        return Err(PatchError::Truncated {
+            at: Position::default(),
+        }
+        .into());
+    }
+
+    if &patch_data[0..16] != ENDSLEY_MAGIC {
+        return Err(PatchError::BadMagic.into());
+    }
+
+    let new_size = offtin(patch_data[16..24].try_into().unwrap());
+    if new_size < 0 {
+        return Err(PatchError::NegativeLength {
+            at: Position::default(),
+            value: new_size,
+        }
+        .into());
+    }
+
+    PatchLimits::check(
+        Position::default(),
+        Limit::NewSize,
+        options.limits.max_new_size,
+        new_size as u64,
+    )?;
+    let new_size = new_size as usize;
+
+    new.clear();
+    try_reserve(new, new_size)?;
+
+    // The header size is stricter than the configured limit
+    let options = PatchOptions {
+        limits: PatchLimits {
+            max_new_size: new_size as u64,
+            ..options.limits
+        },
+        ..options.clone()
+    };
+    let mut stream = bzip2::read::BzDecoder::new(&patch_data[24..]);
+    try_patch(old, &mut stream, new, &options)?;
+
+    if new.len() != new_size {
+        return Err(PatchError::SizeMismatch {
+            at: Position {
+                new_pos: new.len() as u64,
+                ..Position::default()
+            },
+            expected: new_size as u64,
+            actual: new.len() as u64,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endsley_roundtrip() {
+        let old: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 256) as u8).collect();
+        let mut new = old[500..].to_vec();
+        new[42] = 7;
+        new.extend_from_slice(b"tail data");
+
+        let mut patch_data = Vec::new();
+        crate::diff_endsley(&old, &new, &mut patch_data).unwrap();
+        assert!(patch_data.starts_with(ENDSLEY_MAGIC));
+
+        let mut out = Vec::new();
+        patch_endsley(&old, &patch_data, &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn test_endsley_writer_interleaves() {
+        let mut writer = EndsleyWriter::new();
+        let entry = ControlEntry {
+            diff_size: 2,
+            extra_size: 1,
+            offset_increment: 0,
+        };
+        writer.control(entry).unwrap();
+        writer.diff(&[1, 2]).unwrap();
+        writer.extra(&[3]).unwrap();
+        assert_eq!(writer.stream.get_mut().len(), 27);
+        assert_eq!(&writer.stream.get_mut()[24..], &[1, 2, 3]);
+
+        let mut patch_data = Vec::new();
+        writer.close(&mut patch_data).unwrap();
+        let mut out = Vec::new();
+        patch_endsley(&[10, 20], &patch_data, &mut out).unwrap();
+        assert_eq!(out, [11, 22, 3]);
+    }
+
+    #[test]
+    fn test_endsley_size_mismatch() {
+        let old = vec![1u8, 2, 3];
+        let new = vec![1u8, 2, 3, 4];
+        let mut patch_data = Vec::new();
+        crate::diff_endsley(&old, &new, &mut patch_data).unwrap();
+        patch_data[16] = 5;
+
+        let mut out = Vec::new();
+        assert!(patch_endsley(&old, &patch_data, &mut out).is_err());
+    }
+}
@@ -0,0 +1,276 @@
+// error.rs - Typed errors for malformed or mismatched patches
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// One of the three compressed streams of a BSDIFF40/BSDF2 patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Control,
+    Diff,
+    Extra,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stream::Control => "Control",
+            Stream::Diff => "Diff",
+            Stream::Extra => "Extra",
+        })
+    }
+}
+
+/// Which `PatchLimits` bound a patch exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    NewSize,
+    StreamSize(Stream),
+    ControlTuples,
+    /// Old bytes an in-place patch must copy aside to break read/write cycles
+    Scratch,
+    /// Old bytes a rollback journal must keep to undo an in-place patch
+    Journal,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::NewSize => f.write_str("new size"),
+            Limit::StreamSize(stream) => write!(f, "{} stream size", stream),
+            Limit::ControlTuples => f.write_str("control tuple count"),
+            Limit::Scratch => f.write_str("in-place scratch"),
+            Limit::Journal => f.write_str("in-place rollback journal"),
+        }
+    }
+}
+
+/// Where in the patch an error was found.
+///
+/// `tuple` is the index of the control tuple being applied, `old_pos` the read
+/// position in the old file and `new_pos` the number of bytes of the new file
+/// produced so far. Errors found in the header, before any tuple is applied,
+/// report all three as zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub tuple: u64,
+    pub old_pos: u64,
+    pub new_pos: u64,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tuple {}, old offset {}, new offset {}",
+            self.tuple, self.old_pos, self.new_pos
+        )
+    }
+}
+
+/// Why a patch could not be applied.
+///
+/// Patch functions return it wrapped in an `io::Error`, so existing callers keep
+/// working; use `patch_error` to get it back. Truncation and reads past the end
+/// of the old file use `ErrorKind::UnexpectedEof`, everything else `InvalidData`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// The patch does not start with a magic this function understands
+    BadMagic,
+    /// A BSDF2 header names a compression codec this crate does not know
+    UnknownCodec { stream: Stream, value: u8 },
+    /// The patch ends before its header, a stream or a control tuple is complete
+    Truncated { at: Position },
+    /// A length in the header or in a control tuple is negative
+    NegativeLength { at: Position, value: i64 },
+    /// A decompressed stream ended before the control tuples were satisfied
+    StreamExhausted { at: Position, stream: Stream },
+    /// An ADD reads past the end of the old file in `OldBoundsMode::Strict`,
+    /// which usually means the old file is not the one the patch was made against
+    OldOutOfBounds {
+        at: Position,
+        len: u64,
+        old_len: u64,
+    },
+    /// A seek moved the old position before the start of the old file
+    SeekUnderflow { at: Position, seek: i64 },
+    /// A seek moved the old position past what can be represented
+    SeekOverflow { at: Position, seek: i64 },
+    /// The new file does not have, or would exceed, the size recorded in the header
+    SizeMismatch {
+        at: Position,
+        expected: u64,
+        actual: u64,
+    },
+    /// A stream still has data after the last control tuple
+    UnconsumedData { at: Position, stream: Stream },
+    /// The patch needs more than one of the configured `PatchLimits` allows
+    LimitExceeded {
+        at: Position,
+        limit: Limit,
+        max: u64,
+    },
+}
+
+impl PatchError {
+    /// Where the error was found, for errors tied to a position in the patch
+    pub fn position(&self) -> Option<Position> {
+        match *self {
+            PatchError::BadMagic | PatchError::UnknownCodec { .. } => None,
+            PatchError::Truncated { at }
+            | PatchError::NegativeLength { at, .. }
+            | PatchError::StreamExhausted { at, .. }
+            | PatchError::OldOutOfBounds { at, .. }
+            | PatchError::SeekUnderflow { at, .. }
+            | PatchError::SeekOverflow { at, .. }
+            | PatchError::SizeMismatch { at, .. }
+            | PatchError::UnconsumedData { at, .. }
+            | PatchError::LimitExceeded { at, .. } => Some(at),
+        }
+    }
+
+    /// The `io::ErrorKind` used when converting into `io::Error`
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            PatchError::Truncated { .. } | PatchError::OldOutOfBounds { .. } => {
+                io::ErrorKind::UnexpectedEof
+            }
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::BadMagic => f.write_str("Invalid patch magic header"),
+            PatchError::UnknownCodec { stream, value } => write!(
+                f,
+                "Unknown compression algorithm {} for {} stream",
+                value, stream
+            ),
+            PatchError::Truncated { at } => write!(f, "Patch data truncated ({})", at),
+            PatchError::NegativeLength { at, value } => {
+                write!(f, "Negative length {} in patch ({})", value, at)
+            }
+            PatchError::StreamExhausted { at, stream } => {
+                write!(f, "{} data exhausted ({})", stream, at)
+            }
+            PatchError::OldOutOfBounds { at, len, old_len } => write!(
+                f,
+                "Read of {} bytes past end of old file of {} bytes ({})",
+                len, old_len, at
+            ),
+            PatchError::SeekUnderflow { at, seek } => {
+                write!(f, "Seek underflow: seek={} ({})", seek, at)
+            }
+            PatchError::SeekOverflow { at, seek } => {
+                write!(f, "Seek overflow: seek={} ({})", seek, at)
+            }
+            PatchError::SizeMismatch {
+                at,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Size mismatch: expected {}, got {} ({})",
+                expected, actual, at
+            ),
+            PatchError::UnconsumedData { at, stream } => {
+                write!(f, "{} data not fully consumed ({})", stream, at)
+            }
+            PatchError::LimitExceeded { at, limit, max } => {
+                write!(f, "Patch exceeds {} limit of {} ({})", limit, max, at)
+            }
+        }
+    }
+}
+
+impl Error for PatchError {}
+
+impl From<PatchError> for io::Error {
+    fn from(e: PatchError) -> Self {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+/// Returns the `PatchError` behind `err`, if it was caused by a malformed patch.
+///
+/// This looks through a `FileError` to the error it wraps.
+pub fn patch_error(err: &io::Error) -> Option<&PatchError> {
+    let inner = err.get_ref()?;
+    match inner.downcast_ref::<FileError>() {
+        Some(file) => patch_error(&file.source),
+        None => inner.downcast_ref::<PatchError>(),
+    }
+}
+
+/// An error from `diff_files` or `patch_files`, with the path of the file it concerns.
+///
+/// It is returned wrapped in an `io::Error` of the same kind; use `file_error` to
+/// get it back.
+#[derive(Debug)]
+pub struct FileError {
+    path: PathBuf,
+    source: io::Error,
+}
+
+impl FileError {
+    /// The file that could not be read, written, or was found to be malformed
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn wrap(err: io::Error, path: &Path) -> io::Error {
+        let kind = err.kind();
+        let file = FileError {
+            path: path.to_path_buf(),
+            source: err,
+        };
+        io::Error::new(kind, file)
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.source)
+    }
+}
+
+impl Error for FileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Returns the `FileError` behind `err`, if it names the file that failed
+pub fn file_error(err: &io::Error) -> Option<&FileError> {
+    err.get_ref().and_then(|e| e.downcast_ref::<FileError>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_error_roundtrips_through_io_error() {
+        let at = Position {
+            tuple: 3,
+            old_pos: 10,
+            new_pos: 20,
+        };
+        let err: io::Error = PatchError::SeekUnderflow { at, seek: -11 }.into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            patch_error(&err),
+            Some(&PatchError::SeekUnderflow { at, seek: -11 })
+        );
+        assert_eq!(patch_error(&err).unwrap().position(), Some(at));
+
+        let err: io::Error = PatchError::Truncated { at }.into();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(patch_error(&io::Error::new(io::ErrorKind::Other, "other")).is_none());
+    }
+}
@@ -0,0 +1,346 @@
+// extents.rs - Extent-based patching as done by AOSP bspatch and update_engine
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::bsdf2_stream::patch_bsdf2_stream_with_options;
+use crate::control::add_signed;
+use crate::patch::{PatchLimits, PatchOptions};
+
+/// A byte range inside a partition or file.
+///
+/// An offset of `-1` marks a sparse hole: it reads as zeros and discards writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: i64,
+    pub len: u64,
+}
+
+impl Extent {
+    pub fn new(offset: i64, len: u64) -> Self {
+        Self { offset, len }
+    }
+
+    /// A hole of `len` bytes that is not backed by the file
+    pub fn sparse(len: u64) -> Self {
+        Self { offset: -1, len }
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.offset < 0
+    }
+}
+
+fn invalid_extents(s: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid extent list: {:?}", s),
+    )
+}
+
+/// Parse an extent list in AOSP syntax, e.g. `"0:4096,-1:4096,8192:100"`.
+///
+/// An empty string is an empty list.
+pub fn parse_extents(s: &str) -> io::Result<Vec<Extent>> {
+    if s.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    s.split(',')
+        .map(|item| {
+            let (offset, len) = item.split_once(':').ok_or_else(|| invalid_extents(s))?;
+            let offset: i64 = offset.parse().map_err(|_| invalid_extents(s))?;
+            let len: u64 = len.parse().map_err(|_| invalid_extents(s))?;
+            let end = i64::try_from(len).ok().and_then(|l| offset.checked_add(l));
+            if offset < -1 || end.is_none() {
+                return Err(invalid_extents(s));
+            }
+            Ok(Extent { offset, len })
+        })
+        .collect()
+}
+
+/// Maps positions in the virtual concatenation of extents onto the file
+struct ExtentMap {
+    extents: Vec<Extent>,
+    total: u64,
+    pos: u64,
+}
+
+impl ExtentMap {
+    fn new(extents: &[Extent]) -> io::Result<Self> {
+        let total = extents
+            .iter()
+            .try_fold(0u64, |acc, e| acc.checked_add(e.len))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Extent lengths overflow")
+            })?;
+        Ok(Self {
+            extents: extents.to_vec(),
+            total,
+            pos: 0,
+        })
+    }
+
+    /// Returns the extent at the current position and how far into it we are
+    fn locate(&self) -> Option<(Extent, u64)> {
+        let mut start = 0u64;
+        for e in &self.extents {
+            if self.pos < start + e.len {
+                return Some((*e, self.pos - start));
+            }
+            start += e.len;
+        }
+        None
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => add_signed(self.total, d),
+            SeekFrom::Current(d) => add_signed(self.pos, d),
+        };
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Reads a list of extents of `inner` as if they were one contiguous file
+pub struct ExtentReader<R> {
+    inner: R,
+    map: ExtentMap,
+}
+
+impl<R: Read + Seek> ExtentReader<R> {
+    pub fn new(inner: R, extents: &[Extent]) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            map: ExtentMap::new(extents)?,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for ExtentReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (extent, skip) = match self.map.locate() {
+            Some(found) => found,
+            None => return Ok(0),
+        };
+        let want = buf
+            .len()
+            .min((extent.len - skip).min(usize::MAX as u64) as usize);
+
+        let n = if extent.is_sparse() {
+            buf[..want].iter_mut().for_each(|b| *b = 0);
+            want
+        } else {
+            self.inner
+                .seek(SeekFrom::Start(extent.offset as u64 + skip))?;
+            self.inner.read(&mut buf[..want])?
+        };
+        self.map.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for ExtentReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.map.seek(pos)
+    }
+}
+
+/// Writes to a list of extents of `inner` as if they were one contiguous file.
+///
+/// Writing past the end of the last extent is an error.
+pub struct ExtentWriter<W> {
+    inner: W,
+    map: ExtentMap,
+}
+
+impl<W: Write + Seek> ExtentWriter<W> {
+    pub fn new(inner: W, extents: &[Extent]) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            map: ExtentMap::new(extents)?,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write + Seek> Write for ExtentWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (extent, skip) = self.map.locate().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::WriteZero,
+                "Write past end of destination extents",
+            )
+        })?;
+        let want = buf
+            .len()
+            .min((extent.len - skip).min(usize::MAX as u64) as usize);
+
+        let n = if extent.is_sparse() {
+            want
+        } else {
+            self.inner
+                .seek(SeekFrom::Start(extent.offset as u64 + skip))?;
+            self.inner.write(&buf[..want])?
+        };
+        self.map.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for ExtentWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.map.seek(pos)
+    }
+}
+
+/// Apply a BSDF2 or BSDIFF40 patch between extent lists, like
+/// `bspatch old new patch src_extents dst_extents` in AOSP.
+///
+/// The old file is the concatenation of `old_extents` in `old`, and the new file is
+/// written across `new_extents` in `new`. Sparse source extents read as zeros and
+/// sparse destination extents drop what is written to them. No `PatchLimits` apply.
+pub fn patch_bsdf2_extents<O, P, W>(
+    old: O,
+    old_extents: &[Extent],
+    patch: P,
+    new: W,
+    new_extents: &[Extent],
+) -> io::Result<()>
+where
+    O: Read + Seek,
+    P: Read + Seek,
+    W: Write + Seek,
+{
+    patch_bsdf2_extents_with_options(
+        old,
+        old_extents,
+        patch,
+        new,
+        new_extents,
+        &PatchOptions {
+            limits: PatchLimits::unbounded(),
+            ..PatchOptions::default()
+        },
+    )
+}
+
+/// Extent-based patch application with progress reporting, cancellation and limits,
+/// which are applied like in `patch_bsdf2_stream_with_options`.
+pub fn patch_bsdf2_extents_with_options<O, P, W>(
+    old: O,
+    old_extents: &[Extent],
+    patch: P,
+    new: W,
+    new_extents: &[Extent],
+    options: &PatchOptions,
+) -> io::Result<()>
+where
+    O: Read + Seek,
+    P: Read + Seek,
+    W: Write + Seek,
+{
+    let old = ExtentReader::new(old, old_extents)?;
+    let new = ExtentWriter::new(new, new_extents)?;
+    patch_bsdf2_stream_with_options(old, patch, new, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_extents() {
+        assert_eq!(
+            parse_extents("0:4096,-1:10,8192:100").unwrap(),
+            vec![
+                Extent::new(0, 4096),
+                Extent::sparse(10),
+                Extent::new(8192, 100)
+            ]
+        );
+        assert!(parse_extents("").unwrap().is_empty());
+        assert!(parse_extents("0:").is_err());
+        assert!(parse_extents("-2:10").is_err());
+        assert!(parse_extents("5").is_err());
+        assert!(parse_extents("1:2,").is_err());
+    }
+
+    #[test]
+    fn test_extent_reader() {
+        let data: Vec<u8> = (0..20).collect();
+        let extents = [Extent::new(10, 3), Extent::sparse(2), Extent::new(0, 2)];
+        let mut reader = ExtentReader::new(Cursor::new(data), &extents).unwrap();
+
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, [10, 11, 12, 0, 0, 0, 1]);
+
+        assert_eq!(reader.seek(SeekFrom::End(-2)).unwrap(), 5);
+        let mut two = [0u8; 2];
+        reader.read_exact(&mut two).unwrap();
+        assert_eq!(two, [0, 1]);
+    }
+
+    #[test]
+    fn test_extent_writer() {
+        let extents = [Extent::new(4, 2), Extent::sparse(1), Extent::new(0, 2)];
+        let mut writer = ExtentWriter::new(Cursor::new(vec![9u8; 8]), &extents).unwrap();
+        writer.write_all(&[1, 2, 3, 4, 5]).unwrap();
+        assert!(writer.write_all(&[6]).is_err());
+        assert_eq!(writer.into_inner().into_inner(), [4, 5, 9, 9, 1, 2, 9, 9]);
+    }
+
+    #[test]
+    fn test_patch_between_extents() {
+        let old: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        let mut new = old[1000..3000].to_vec();
+        new[10] ^= 0xFF;
+
+        let mut patch = Vec::new();
+        crate::diff_bsdiff40(&old, &new, &mut patch).unwrap();
+
+        // Old lives in two pieces of a partition, new is split around a hole
+        let mut partition = vec![0u8; 8000];
+        partition[4000..5000].copy_from_slice(&old[..1000]);
+        partition[1000..3000].copy_from_slice(&old[1000..]);
+        let old_extents = parse_extents("4000:1000,1000:2000").unwrap();
+        let new_extents = parse_extents("6000:1500,-1:0,0:500").unwrap();
+
+        let mut target = Cursor::new(vec![0u8; 8000]);
+        patch_bsdf2_extents(
+            Cursor::new(&partition),
+            &old_extents,
+            Cursor::new(&patch),
+            &mut target,
+            &new_extents,
+        )
+        .unwrap();
+
+        let target = target.into_inner();
+        assert_eq!(&target[6000..7500], &new[..1500]);
+        assert_eq!(&target[0..500], &new[1500..]);
+    }
+}
@@ -0,0 +1,367 @@
+// files.rs - Diff and patch files on disk without reading them onto the heap
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use memmap2::Mmap;
+
+use crate::bsdf2_stream::patch_bsdf2_stream_with_options;
+use crate::bsdf2_writer::CompressionAlgorithm;
+use crate::diff::{diff_bsdf2_with_options, DiffOptions};
+use crate::endsley::patch_endsley_with_options;
+use crate::error::{file_error, FileError};
+use crate::format::{detect_format, PatchFormat};
+use crate::patch::{try_patch, PatchOptions};
+use crate::progress::is_cancelled;
+
+/// Diff two files and write a BSDF2 patch with Brotli-compressed streams.
+///
+/// Both inputs are memory-mapped read-only, so they are paged in by the OS
+/// instead of copied onto the heap. The patch is written to a temporary file
+/// next to `patch_path` and renamed over it once complete, so readers never
+/// see a partial patch. Errors carry the path of the file that failed; see
+/// `file_error`.
+pub fn diff_files<P, Q, R>(
+    old_path: P,
+    new_path: Q,
+    patch_path: R,
+    options: &DiffOptions,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let old = map(old_path.as_ref())?;
+    let new = map(new_path.as_ref())?;
+    write_atomically(patch_path.as_ref(), |out| {
+        let alg = CompressionAlgorithm::Brotli;
+        diff_bsdf2_with_options(&old, &new, out, alg, alg, alg, options)
+    })
+}
+
+/// Apply a patch in any supported format to a file, returning the format detected.
+///
+/// The old file and the patch are memory-mapped read-only. BSDIFF40 and BSDF2
+/// patches are streamed to the output, so the new file is never held in memory
+/// either; raw and ENDSLEY patches are applied in memory and then written out. All
+/// formats are subject to `options.limits`: callers expecting new files over the
+/// 2 GiB default must raise `max_new_size` and `max_stream_size`.
+///
+/// The output goes to a temporary file next to `new_path` that is renamed over it
+/// once complete; on failure `new_path` is left untouched. Errors carry the path
+/// of the file that failed, and malformed patches report the patch path with the
+/// `PatchError` available through `patch_error`.
+pub fn patch_files<P, Q, R>(
+    old_path: P,
+    patch_path: Q,
+    new_path: R,
+    options: &PatchOptions,
+) -> io::Result<PatchFormat>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let patch_path = patch_path.as_ref();
+    let old = map(old_path.as_ref())?;
+    let patch = map(patch_path)?;
+    let format = detect_format(&patch);
+
+    write_atomically(new_path.as_ref(), |out| {
+        let result = match format {
+            PatchFormat::Bsdiff40 | PatchFormat::Bsdf2 => patch_bsdf2_stream_with_options(
+                Cursor::new(&old[..]),
+                Cursor::new(&patch[..]),
+                &mut *out,
+                options,
+            ),
+            PatchFormat::Raw => {
+                let mut new = Vec::new();
+                try_patch(&old, &mut &patch[..], &mut new, options)
+                    .and_then(|()| out.write_all(&new))
+            }
+            PatchFormat::Endsley => {
+                let mut new = Vec::new();
+                patch_endsley_with_options(&old, &patch, &mut new, options)
+                    .and_then(|()| out.write_all(&new))
+            }
+        };
+        // Anything not already tied to the output or a cancellation is about the patch
+        result.map_err(|e| {
+            if file_error(&e).is_some() || is_cancelled(&e) {
+                e
+            } else {
+                FileError::wrap(e, patch_path)
+            }
+        })
+    })?;
+    Ok(format)
+}
+
+/// Map a whole file read-only
+fn map(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path).map_err(|e| FileError::wrap(e, path))?;
+    // SAFETY: the mapping is read-only. As with any mapped file, the caller must
+    // not truncate or rewrite the input while it is in use.
+    unsafe { Mmap::map(&file) }.map_err(|e| FileError::wrap(e, path))
+}
+
+/// Distinguishes the temporary files of calls made by this process
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Create a temporary file next to `path` that no other call is using.
+///
+/// The name carries the process id and a per-call counter, and is opened with
+/// `create_new`, so a name left behind by a crashed process is skipped rather
+/// than reused.
+fn create_temp(path: &Path) -> io::Result<(File, PathBuf)> {
+    let name = path.file_name().ok_or_else(|| {
+        let err = io::Error::new(io::ErrorKind::InvalidInput, "Output path has no file name");
+        FileError::wrap(err, path)
+    })?;
+    loop {
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(name);
+        let call = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        temp_name.push(format!(".{}.{}.tmp", std::process::id(), call));
+        let temp = path.with_file_name(temp_name);
+
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((file, temp)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(FileError::wrap(e, &temp)),
+        }
+    }
+}
+
+/// Write `path` through a temporary file that replaces it only once `write` succeeds
+fn write_atomically<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut PathWriter<BufWriter<File>>) -> io::Result<()>,
+{
+    let (file, temp) = create_temp(path)?;
+    let mut out = PathWriter {
+        inner: BufWriter::new(file),
+        path: path.to_path_buf(),
+    };
+    let result = write(&mut out)
+        .and_then(|()| out.flush())
+        .and_then(|()| {
+            out.inner
+                .get_ref()
+                .sync_all()
+                .map_err(|e| FileError::wrap(e, path))
+        })
+        .and_then(|()| fs::rename(&temp, path).map_err(|e| FileError::wrap(e, path)));
+    if result.is_err() {
+        drop(out);
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Tags write errors with the path of the file being written
+struct PathWriter<W> {
+    inner: W,
+    path: PathBuf,
+}
+
+impl<W: Write> Write for PathWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner
+            .write(buf)
+            .map_err(|e| FileError::wrap(e, &self.path))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner
+            .flush()
+            .map_err(|e| FileError::wrap(e, &self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{patch_error, PatchError};
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("bsdiff-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_diff_and_patch_files() {
+        let dir = TempDir::new("files");
+        let path = |name: &str| dir.0.join(name);
+
+        let old: Vec<u8> = (0..50_000u32).map(|i| (i * 17 % 253) as u8).collect();
+        let mut new = old[1000..].to_vec();
+        new.extend_from_slice(b"appended");
+        fs::write(path("old"), &old).unwrap();
+        fs::write(path("new"), &new).unwrap();
+
+        diff_files(
+            path("old"),
+            path("new"),
+            path("patch"),
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        let format = patch_files(
+            path("old"),
+            path("patch"),
+            path("out"),
+            &PatchOptions::default(),
+        );
+        assert_eq!(format.unwrap(), PatchFormat::Bsdf2);
+        assert!(fs::read(path("out")).unwrap() == new);
+
+        // Other formats go through the same entry point, and empty files map fine
+        let mut raw = Vec::new();
+        crate::diff(&old, &new, &mut raw).unwrap();
+        fs::write(path("raw"), &raw).unwrap();
+        let format = patch_files(
+            path("old"),
+            path("raw"),
+            path("out"),
+            &PatchOptions::default(),
+        );
+        assert_eq!(format.unwrap(), PatchFormat::Raw);
+        assert!(fs::read(path("out")).unwrap() == new);
+
+        fs::write(path("empty"), b"").unwrap();
+        diff_files(
+            path("empty"),
+            path("empty"),
+            path("patch"),
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        patch_files(
+            path("empty"),
+            path("patch"),
+            path("out"),
+            &PatchOptions::default(),
+        )
+        .unwrap();
+        assert!(fs::read(path("out")).unwrap().is_empty());
+
+        // Only the inputs and outputs are left, no temporary files
+        let mut names: Vec<_> = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["empty", "new", "old", "out", "patch", "raw"]);
+    }
+
+    #[test]
+    fn test_concurrent_calls_use_their_own_temp_files() {
+        let dir = TempDir::new("files-concurrent");
+        let path = |name: &str| dir.0.join(name);
+
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * 13 % 251) as u8).collect();
+        let new: Vec<u8> = old.iter().rev().copied().collect();
+        fs::write(path("old"), &old).unwrap();
+        fs::write(path("new"), &new).unwrap();
+        diff_files(
+            path("old"),
+            path("new"),
+            path("patch"),
+            &DiffOptions::default(),
+        )
+        .unwrap();
+
+        // A temporary file still held by another call is left alone
+        let (_, taken) = create_temp(&path("out")).unwrap();
+        assert!(taken.exists());
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    patch_files(
+                        path("old"),
+                        path("patch"),
+                        path("out"),
+                        &PatchOptions::default(),
+                    )
+                    .unwrap()
+                });
+            }
+        });
+        assert!(fs::read(path("out")).unwrap() == new);
+        fs::remove_file(taken).unwrap();
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_file_errors_carry_the_path() {
+        let dir = TempDir::new("file-errors");
+        let path = |name: &str| dir.0.join(name);
+        fs::write(path("old"), [1u8; 100]).unwrap();
+
+        let err = patch_files(
+            path("missing"),
+            path("old"),
+            path("out"),
+            &PatchOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(file_error(&err).unwrap().path(), path("missing"));
+        assert!(err.to_string().contains("missing"));
+
+        // A malformed patch names the patch and leaves no output behind
+        let mut patch = Vec::new();
+        crate::diff_bsdf2_uniform(
+            &[1; 100],
+            &[2; 100],
+            &mut patch,
+            CompressionAlgorithm::Brotli,
+        )
+        .unwrap();
+        patch[5] = 9;
+        fs::write(path("patch"), &patch).unwrap();
+        let err = patch_files(
+            path("old"),
+            path("patch"),
+            path("out"),
+            &PatchOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(file_error(&err).unwrap().path(), path("patch"));
+        assert!(matches!(
+            patch_error(&err),
+            Some(PatchError::UnknownCodec { value: 9, .. })
+        ));
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 2);
+
+        let err = diff_files(
+            path("old"),
+            path("old"),
+            path("no-such-dir/patch"),
+            &DiffOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            file_error(&err).unwrap().path().parent(),
+            Some(&*path("no-such-dir"))
+        );
+    }
+}
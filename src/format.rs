@@ -0,0 +1,107 @@
+// format.rs - Patch format detection
+
+use std::io;
+
+use crate::bsdf2::{patch_bsdf2, BSDF2_MAGIC, BSDIFF_MAGIC};
+use crate::endsley::{patch_endsley, ENDSLEY_MAGIC};
+use crate::patch::patch;
+
+/// Container format of a patch, as recognized by its leading bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    /// Headerless control/diff/extra stream, as produced by `diff()`
+    Raw,
+    /// Classic `BSDIFF40` patch with bzip2-compressed streams
+    Bsdiff40,
+    /// Android `BSDF2` patch with a codec byte per stream
+    Bsdf2,
+    /// `ENDSLEY/BSDIFF43` patch with one interleaved bzip2 stream
+    Endsley,
+}
+
+/// Identify the format of a patch from its magic bytes.
+///
+/// Anything that does not start with `BSDIFF40`, `BSDF2` or `ENDSLEY/BSDIFF43` is
+/// treated as a raw patch. A raw patch cannot be confused with the others in practice:
+/// its first eight bytes encode the length of the first ADD, and every magic would
+/// decode to a length of several petabytes.
+pub fn detect_format(patch: &[u8]) -> PatchFormat {
+    if patch.starts_with(BSDIFF_MAGIC) {
+        PatchFormat::Bsdiff40
+    } else if patch.starts_with(BSDF2_MAGIC) {
+        PatchFormat::Bsdf2
+    } else if patch.starts_with(ENDSLEY_MAGIC) {
+        PatchFormat::Endsley
+    } else {
+        PatchFormat::Raw
+    }
+}
+
+/// Apply a patch in any supported format, returning the format that was detected.
+///
+/// `new` is cleared before the patched file is written into it.
+pub fn apply_any_patch(
+    old: &[u8],
+    patch_data: &[u8],
+    new: &mut Vec<u8>,
+) -> io::Result<PatchFormat> {
+    let format = detect_format(patch_data);
+    match format {
+        PatchFormat::Raw => {
+            new.clear();
+            patch(old, &mut &patch_data[..], new)?;
+        }
+        PatchFormat::Bsdiff40 | PatchFormat::Bsdf2 => patch_bsdf2(old, patch_data, new)?,
+        PatchFormat::Endsley => patch_endsley(old, patch_data, new)?,
+    }
+    Ok(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompressionAlgorithm;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format(b"BSDIFF40........"), PatchFormat::Bsdiff40);
+        assert_eq!(detect_format(b"BSDF2\x01\x02\x00"), PatchFormat::Bsdf2);
+        assert_eq!(
+            detect_format(b"ENDSLEY/BSDIFF43\0\0\0\0\0\0\0\0"),
+            PatchFormat::Endsley
+        );
+        assert_eq!(detect_format(&[0u8; 24]), PatchFormat::Raw);
+        assert_eq!(detect_format(&[]), PatchFormat::Raw);
+    }
+
+    #[test]
+    fn test_apply_all_formats() {
+        let old: Vec<u8> = (0..4096u32).map(|i| (i % 97) as u8).collect();
+        let mut new = old.clone();
+        new[100..120].copy_from_slice(&[0xAA; 20]);
+        new.extend_from_slice(b"appended");
+
+        let mut raw = Vec::new();
+        crate::diff(&old, &new, &mut raw).unwrap();
+        let mut bsdiff40 = Vec::new();
+        crate::diff_bsdiff40(&old, &new, &mut bsdiff40).unwrap();
+        let mut bsdf2 = Vec::new();
+        crate::diff_bsdf2_uniform(&old, &new, &mut bsdf2, CompressionAlgorithm::Brotli).unwrap();
+        let mut endsley = Vec::new();
+        crate::diff_endsley(&old, &new, &mut endsley).unwrap();
+
+        for (patch_data, expected) in [
+            (raw, PatchFormat::Raw),
+            (bsdiff40, PatchFormat::Bsdiff40),
+            (bsdf2, PatchFormat::Bsdf2),
+            (endsley, PatchFormat::Endsley),
+        ] {
+            let mut out = b"stale".to_vec();
+            assert_eq!(
+                apply_any_patch(&old, &patch_data, &mut out).unwrap(),
+                expected
+            );
+            assert_eq!(out, new);
+        }
+    }
+}
@@ -0,0 +1,1070 @@
+// inplace.rs - Patch application that turns old into new within the same storage
+//
+// Every control tuple reads an old range and writes a new range. Applied in place,
+// a tuple's write destroys old bytes that other tuples may still need, so the
+// tuples are reordered: a tuple that reads a range runs before any tuple that
+// overwrites it. Cycles in that order are broken by copying the old range of one
+// tuple per cycle to a scratch area before anything is written.
+//
+// The output is written in chunks. Before each chunk the journal records what the
+// chunk is about to overwrite, so after a crash the chunk in flight is rolled back
+// and the patch resumes from there. By default only the latest chunk's undo data
+// is kept, in two alternating slots. A rollback journal appends every chunk's undo
+// data instead, plus the old bytes the final shrink cuts off, so the old file can
+// be put back at any point; that is a copy of the whole old file, which is why it
+// is opt-in and bounded by a budget.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::bsdf2::parse_with_limits;
+use crate::bsdf2_writer::ControlEntry;
+use crate::checkpoint::Checkpoint;
+use crate::error::{Limit, PatchError, Position};
+use crate::parallel::{resolve_ops, Op};
+use crate::patch::{try_reserve, PatchLimits, PatchOptions};
+use crate::simd;
+use crate::suffix::content_hash;
+
+const JOURNAL_MAGIC: &[u8; 8] = b"BSDINPL1";
+const META_SIZE: usize = 72;
+const SLOT_HEADER_SIZE: usize = 48;
+
+/// Bytes of the new file written per journaled step
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// What the journal of an in-place patch keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JournalMode {
+    /// Only the undo data of the write in flight, so an interrupted patch can be
+    /// resumed. This is the default.
+    #[default]
+    Resume,
+    /// The undo data of every write, so an interrupted or even completed patch can
+    /// also be undone with `rollback_bsdf2_in_place`. This keeps every old byte that
+    /// is overwritten or cut off, i.e. the whole old file; patching fails with
+    /// `Limit::Journal` before anything is written if that is more than `budget`.
+    Rollback { budget: u64 },
+}
+
+/// Random-access storage for an in-place patch target or its journal
+pub trait Storage: Read + Write + Seek {
+    /// Grow or shrink the storage to `len` bytes
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Make everything written so far durable
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl Storage for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl Storage for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(to_usize(len)?, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Storage for Cursor<&mut Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(to_usize(len)?, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Apply a BSDF2 or BSDIFF40 patch to `target` in place.
+///
+/// `target` holds the old file and is turned into the new one, growing or shrinking
+/// as needed. At most `scratch_limit` bytes of old data are copied aside to break
+/// read/write cycles; patches that need more fail with `Limit::Scratch`.
+///
+/// `journal` records progress. If a previous call for the same patch was
+/// interrupted, the write that was in flight is rolled back and patching resumes
+/// from there, so pass the same journal again after a crash. An empty journal
+/// starts from the beginning. The journal is left holding a completion record,
+/// which makes calling again a no-op; discard it once the update is recorded.
+///
+/// Only the write in flight can be undone here. To be able to go back to the old
+/// file instead of resuming, patch with `JournalMode::Rollback` in
+/// `PatchOptions::in_place_journal` and call `rollback_bsdf2_in_place`.
+pub fn patch_bsdf2_in_place<S: Storage, J: Storage>(
+    target: &mut S,
+    patch_data: &[u8],
+    journal: &mut J,
+    scratch_limit: u64,
+) -> io::Result<()> {
+    patch_bsdf2_in_place_with_options(
+        target,
+        patch_data,
+        journal,
+        scratch_limit,
+        &PatchOptions::default(),
+    )
+}
+
+/// In-place patch application with limits, progress reporting and cancellation.
+///
+/// Cancelling leaves `target` half patched, but the journal is consistent and a
+/// later call resumes where this one stopped. Resuming must use the same
+/// `in_place_journal` mode as the call that started the journal.
+pub fn patch_bsdf2_in_place_with_options<S: Storage, J: Storage>(
+    target: &mut S,
+    patch_data: &[u8],
+    journal: &mut J,
+    scratch_limit: u64,
+    options: &PatchOptions,
+) -> io::Result<()> {
+    apply(
+        target,
+        patch_data,
+        journal,
+        scratch_limit,
+        CHUNK_SIZE,
+        options,
+    )
+}
+
+/// Turn `target` back into the old file, undoing an in-place patch that was
+/// started with `JournalMode::Rollback`.
+///
+/// `patch_data` and `journal` must be those of the interrupted (or completed)
+/// call. Every write recorded in the journal is undone and `target` is restored to
+/// the old size; the journal is then emptied, so patching again starts afresh. An
+/// empty journal means nothing was written yet, and there is nothing to undo. Like
+/// patching, this can be interrupted and called again.
+pub fn rollback_bsdf2_in_place<S: Storage, J: Storage>(
+    target: &mut S,
+    patch_data: &[u8],
+    journal: &mut J,
+) -> io::Result<()> {
+    let patch_len = patch_data.len() as u64;
+    let patch_id = Checkpoint::patch_id(&mut Cursor::new(patch_data), 0, patch_len)?;
+    let meta = match read_meta(journal, patch_id)? {
+        Some(meta) => meta,
+        None => return Ok(()),
+    };
+    if !meta.rollback {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "In-place journal was not written in rollback mode",
+        ));
+    }
+
+    // Each byte is written once, so the records can be replayed in any order.
+    // Records past the end of old carry no undo and must not grow the target again.
+    target.set_len(meta.old_len)?;
+    read_log(journal, &meta, |slot| {
+        if slot.undo.is_empty() {
+            return Ok(());
+        }
+        write_at(target, slot.pos, &slot.undo)
+    })?;
+    target.sync()?;
+    journal.set_len(0)?;
+    journal.sync()
+}
+
+/// The order an in-place patch runs its tuples in
+#[derive(Debug)]
+struct Plan {
+    /// Tuple indices in the order they run
+    order: Vec<usize>,
+    /// Tuples whose old range is copied to scratch before anything is written
+    stashed: Vec<usize>,
+}
+
+/// Order tuples so that each reads its old range before another tuple overwrites it.
+///
+/// `reads[i]` and `writes[i]` are tuple `i`'s old and new ranges; the writes are
+/// ascending and disjoint. A tuple overlapping its own read is not a conflict, it
+/// is applied like `memmove`. When every remaining tuple waits on another, the one
+/// with the smallest read is stashed. Fails with the index of the tuple that would
+/// take the stash past `scratch_limit`.
+fn plan(reads: &[Range<u64>], writes: &[Range<u64>], scratch_limit: u64) -> Result<Plan, usize> {
+    let n = reads.len();
+
+    // a -> b when b writes over part of what a reads, so a must run first
+    let mut successors = vec![Vec::new(); n];
+    let mut waiting_on = vec![0usize; n];
+    for (a, read) in reads.iter().enumerate() {
+        let first = writes.partition_point(|w| w.end <= read.start);
+        for (b, write) in writes.iter().enumerate().skip(first) {
+            if write.start >= read.end {
+                break;
+            }
+            if b != a && write.start < write.end {
+                successors[a].push(b);
+                waiting_on[b] += 1;
+            }
+        }
+    }
+
+    let mut ready: BinaryHeap<Reverse<usize>> = (0..n)
+        .filter(|&i| waiting_on[i] == 0)
+        .map(Reverse)
+        .collect();
+    let mut candidates: BinaryHeap<Reverse<(u64, usize)>> = (0..n)
+        .filter(|&i| !successors[i].is_empty())
+        .map(|i| Reverse((reads[i].end - reads[i].start, i)))
+        .collect();
+    let mut released = vec![false; n];
+    let mut order = Vec::with_capacity(n);
+    let mut stashed = Vec::new();
+    let mut scratch = 0u64;
+
+    while order.len() < n {
+        let a = match ready.pop() {
+            Some(Reverse(a)) => {
+                order.push(a);
+                a
+            }
+            None => {
+                // Everything left is on or behind a cycle; stash the cheapest tuple
+                // that still holds others back
+                let a = loop {
+                    let Reverse((_, a)) = candidates.pop().expect("a stalled plan has a cycle");
+                    if !released[a] {
+                        break a;
+                    }
+                };
+                scratch += reads[a].end - reads[a].start;
+                if scratch > scratch_limit {
+                    return Err(a);
+                }
+                stashed.push(a);
+                a
+            }
+        };
+
+        if !released[a] {
+            released[a] = true;
+            for &b in &successors[a] {
+                waiting_on[b] -= 1;
+                if waiting_on[b] == 0 {
+                    ready.push(Reverse(b));
+                }
+            }
+        }
+    }
+
+    Ok(Plan { order, stashed })
+}
+
+/// Rewrite `entries` so `patch_bsdf2_in_place` can apply them with at most
+/// `scratch_limit` bytes of scratch.
+///
+/// Cycles the scratch cannot cover are broken by turning the ADD of one of their
+/// tuples into extra data, smallest read first. The new file stays the same; the
+/// patch grows by roughly the bytes converted.
+pub(crate) fn make_in_place_safe(entries: &mut [ControlEntry], old_len: usize, scratch_limit: u64) {
+    // One pass planned with unlimited scratch: keep what fits, convert the rest
+    let (reads, writes) = accesses(entries, old_len);
+    if let Ok(plan) = plan(&reads, &writes, u64::MAX) {
+        let mut scratch = 0;
+        for &i in &plan.stashed {
+            let len = reads[i].end - reads[i].start;
+            if scratch + len <= scratch_limit {
+                scratch += len;
+            } else {
+                to_extra(&mut entries[i]);
+            }
+        }
+    }
+
+    // The applier plans the converted patch afresh; convert until it fits there too
+    loop {
+        let (reads, writes) = accesses(entries, old_len);
+        match plan(&reads, &writes, scratch_limit) {
+            Ok(_) => return,
+            Err(i) => to_extra(&mut entries[i]),
+        }
+    }
+}
+
+/// Turn the ADD of `entry` into extra data without moving later tuples
+fn to_extra(entry: &mut ControlEntry) {
+    entry.offset_increment += entry.diff_size;
+    entry.extra_size += entry.diff_size;
+    entry.diff_size = 0;
+}
+
+/// The old range each entry reads and the new range it writes
+fn accesses(entries: &[ControlEntry], old_len: usize) -> (Vec<Range<u64>>, Vec<Range<u64>>) {
+    let (mut old_pos, mut new_pos) = (0i64, 0u64);
+    entries
+        .iter()
+        .map(|entry| {
+            let read = old_range(old_pos.max(0) as usize, entry.diff_size as usize, old_len);
+            let len = (entry.diff_size + entry.extra_size) as u64;
+            let write = new_pos..new_pos + len;
+            old_pos += entry.diff_size + entry.offset_increment;
+            new_pos += len;
+            (read, write)
+        })
+        .unzip()
+}
+
+/// The old range a tuple reads, clipped to the old file
+fn old_range(old_start: usize, add_len: usize, old_len: usize) -> Range<u64> {
+    let end = old_start.saturating_add(add_len).min(old_len);
+    old_start.min(end) as u64..end as u64
+}
+
+/// Journal header, written once the stash is in place
+struct Meta {
+    patch_id: u64,
+    old_len: u64,
+    new_size: u64,
+    chunk: u64,
+    stash_len: u64,
+    stash_hash: u64,
+    /// Slots are appended rather than alternated, see `JournalMode::Rollback`
+    rollback: bool,
+}
+
+impl Meta {
+    /// Where the slots start, after the header and the stash
+    fn slots_start(&self) -> u64 {
+        META_SIZE as u64 + self.stash_len
+    }
+
+    /// Where slot `seq` goes: one of two alternating areas, or in a rollback journal
+    /// `log_end`, the end of the previous slot
+    fn slot_offset(&self, seq: u64, log_end: u64) -> u64 {
+        if self.rollback {
+            log_end
+        } else {
+            self.slots_start() + (seq % 2) * (SLOT_HEADER_SIZE as u64 + self.chunk)
+        }
+    }
+}
+
+/// The chunk about to be written, and the bytes it overwrites
+struct Slot {
+    seq: u64,
+    step: u64,
+    index: u64,
+    pos: u64,
+    undo: Vec<u8>,
+}
+
+/// What an earlier, interrupted call left in the journal
+struct Journaled {
+    meta: Meta,
+    stash: Vec<u8>,
+    /// The latest chunk started, if any
+    slot: Option<Slot>,
+    /// End of the latest slot in a rollback journal
+    log_end: u64,
+}
+
+fn apply<S: Storage, J: Storage>(
+    target: &mut S,
+    patch_data: &[u8],
+    journal: &mut J,
+    scratch_limit: u64,
+    chunk: usize,
+    options: &PatchOptions,
+) -> io::Result<()> {
+    let (new_size, control, diff, extra) = parse_with_limits(patch_data, &options.limits)?;
+    let patch_len = patch_data.len() as u64;
+    let patch_id = Checkpoint::patch_id(&mut Cursor::new(patch_data), 0, patch_len)?;
+
+    let rollback = match options.in_place_journal {
+        JournalMode::Resume => None,
+        JournalMode::Rollback { budget } => Some(budget),
+    };
+    let resume = read_journal(journal, patch_id)?;
+    let (old_len, chunk) = match &resume {
+        Some(journaled) => (
+            to_usize(journaled.meta.old_len)?,
+            to_usize(journaled.meta.chunk)?,
+        ),
+        None => (to_usize(target.seek(SeekFrom::End(0))?)?, chunk),
+    };
+    let new_size = new_size as usize;
+
+    let ops = resolve_ops(old_len, &control, &diff, &extra, new_size, options)?;
+    let reads: Vec<Range<u64>> = ops
+        .iter()
+        .map(|op| old_range(op.old_start, op.add_len, old_len))
+        .collect();
+    let writes: Vec<Range<u64>> = ops
+        .iter()
+        .map(|op| op.new_start as u64..op.new_end() as u64)
+        .collect();
+    let plan = plan(&reads, &writes, scratch_limit).map_err(|i| PatchError::LimitExceeded {
+        at: Position {
+            tuple: i as u64,
+            old_pos: ops[i].old_start as u64,
+            new_pos: ops[i].new_start as u64,
+        },
+        limit: Limit::Scratch,
+        max: scratch_limit,
+    })?;
+
+    let mut stash_at = vec![None; ops.len()];
+    let mut stash_len = 0;
+    for &a in &plan.stashed {
+        stash_at[a] = Some(stash_len);
+        stash_len += (reads[a].end - reads[a].start) as usize;
+    }
+
+    let (meta, stash, slot, mut log_end) = match resume {
+        Some(Journaled {
+            meta,
+            stash,
+            slot,
+            log_end,
+        }) => {
+            if meta.new_size != new_size as u64 || meta.stash_len != stash_len as u64 {
+                return Err(invalid("In-place journal does not match this patch"));
+            }
+            if meta.rollback != rollback.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "In-place journal was written in a different JournalMode",
+                ));
+            }
+            (meta, stash, slot, log_end)
+        }
+        None => {
+            // Every old byte is either overwritten or cut off by the final shrink
+            if let Some(budget) = rollback {
+                PatchLimits::check(Position::default(), Limit::Journal, budget, old_len as u64)?;
+            }
+
+            // Copy the stashed ranges aside before anything is written
+            let mut stash = Vec::new();
+            try_reserve(&mut stash, stash_len)?;
+            for &a in &plan.stashed {
+                let start = stash.len();
+                stash.resize(start + (reads[a].end - reads[a].start) as usize, 0);
+                read_at(target, reads[a].start, &mut stash[start..])?;
+            }
+            let meta = Meta {
+                patch_id,
+                old_len: old_len as u64,
+                new_size: new_size as u64,
+                chunk: chunk as u64,
+                stash_len: stash_len as u64,
+                stash_hash: content_hash(&stash),
+                rollback: rollback.is_some(),
+            };
+            write_journal(journal, &meta, &stash)?;
+            let log_end = meta.slots_start();
+            (meta, stash, None, log_end)
+        }
+    };
+
+    if new_size > old_len {
+        target.set_len(new_size as u64)?;
+    }
+
+    // Roll back the chunk that was in flight and continue from it
+    let (mut step, mut index, mut seq) = (0, 0, 0);
+    if let Some(slot) = slot {
+        write_at(target, slot.pos, &slot.undo)?;
+        target.sync()?;
+        step = to_usize(slot.step)?;
+        index = to_usize(slot.index)?;
+        seq = slot.seq + 1;
+    }
+
+    let mut reporter = options.reporter(new_size as u64);
+    let mut done = plan.order[..step.min(plan.order.len())]
+        .iter()
+        .map(|&a| (ops[a].add_len + ops[a].copy_len) as u64)
+        .sum::<u64>();
+    if let Some(&a) = plan.order.get(step) {
+        done += (0..index)
+            .map(|i| chunk_range(&ops[a], chunk, i).len() as u64)
+            .sum::<u64>();
+    }
+
+    let (mut old_buf, mut out, mut undo) = (Vec::new(), Vec::new(), Vec::new());
+    while step < plan.order.len() {
+        let a = plan.order[step];
+        let op = &ops[a];
+        let stashed =
+            stash_at[a].map(|start| &stash[start..][..(reads[a].end - reads[a].start) as usize]);
+
+        while index < chunk_count(op, chunk) {
+            reporter.update(done)?;
+            let range = chunk_range(op, chunk, index);
+            let pos = (op.new_start + range.start) as u64;
+
+            // Compute the chunk before touching anything
+            out.clear();
+            if range.start < op.add_len {
+                out.extend_from_slice(
+                    &diff[op.diff_start + range.start..op.diff_start + range.end],
+                );
+                let old_start = op.old_start.saturating_add(range.start);
+                let avail = old_len.saturating_sub(old_start).min(range.len());
+                match stashed {
+                    Some(stashed) => simd::add_bytes(&mut out, &stashed[range.start..][..avail]),
+                    None => {
+                        old_buf.resize(avail, 0);
+                        read_at(target, old_start as u64, &mut old_buf)?;
+                        simd::add_bytes(&mut out, &old_buf);
+                    }
+                }
+            } else {
+                let start = op.extra_start + range.start - op.add_len;
+                out.extend_from_slice(&extra[start..start + range.len()]);
+            }
+
+            // Bytes past the end of old are new data, and nothing needs them back
+            undo.resize(old_len.saturating_sub(pos as usize).min(range.len()), 0);
+            read_at(target, pos, &mut undo)?;
+            let slot = Slot {
+                seq,
+                step: step as u64,
+                index: index as u64,
+                pos,
+                undo,
+            };
+            log_end = write_slot(journal, &meta, &slot, log_end)?;
+            undo = slot.undo;
+
+            write_at(target, pos, &out)?;
+            target.sync()?;
+            seq += 1;
+            index += 1;
+            done += range.len() as u64;
+        }
+        step += 1;
+        index = 0;
+    }
+
+    // A rollback journal also keeps the old bytes the shrink cuts off, as slots of
+    // the step after the last one
+    let tail = new_size..old_len.max(new_size);
+    let tail_chunks = if meta.rollback {
+        (tail.len() + chunk - 1) / chunk
+    } else {
+        0
+    };
+    while index < tail_chunks {
+        let pos = tail.start + index * chunk;
+        undo.resize(chunk.min(tail.end - pos), 0);
+        read_at(target, pos as u64, &mut undo)?;
+        let slot = Slot {
+            seq,
+            step: plan.order.len() as u64,
+            index: index as u64,
+            pos: pos as u64,
+            undo,
+        };
+        log_end = write_slot(journal, &meta, &slot, log_end)?;
+        undo = slot.undo;
+        seq += 1;
+        index += 1;
+    }
+
+    // Record completion before shrinking, which discards old bytes for good
+    let slot = Slot {
+        seq,
+        step: plan.order.len() as u64,
+        index: tail_chunks as u64,
+        pos: 0,
+        undo: Vec::new(),
+    };
+    write_slot(journal, &meta, &slot, log_end)?;
+    target.set_len(new_size as u64)?;
+    target.sync()?;
+    reporter.report(new_size as u64);
+    Ok(())
+}
+
+fn chunk_count(op: &Op, chunk: usize) -> usize {
+    (op.add_len + chunk - 1) / chunk + (op.copy_len + chunk - 1) / chunk
+}
+
+/// The bytes of `op`'s output, relative to its start, written by chunk `index`
+fn chunk_range(op: &Op, chunk: usize, index: usize) -> Range<usize> {
+    let add_chunks = (op.add_len + chunk - 1) / chunk;
+    if index < add_chunks {
+        // Walk the ADD backwards when it writes above where it reads, like memmove
+        let k = if op.new_start > op.old_start {
+            add_chunks - 1 - index
+        } else {
+            index
+        };
+        k * chunk..((k + 1) * chunk).min(op.add_len)
+    } else {
+        let k = index - add_chunks;
+        let end = op.add_len + op.copy_len;
+        op.add_len + k * chunk..(op.add_len + (k + 1) * chunk).min(end)
+    }
+}
+
+/// Read the journal header, or `None` if there is no complete one, meaning
+/// nothing was written to the target yet
+fn read_meta<J: Storage>(journal: &mut J, patch_id: u64) -> io::Result<Option<Meta>> {
+    let mut header = [0u8; META_SIZE];
+    journal.seek(SeekFrom::Start(0))?;
+    if !read_full(journal, &mut header)? || &header[..8] != JOURNAL_MAGIC {
+        return Ok(None);
+    }
+    let field = |i: usize| u64::from_le_bytes(header[i * 8..i * 8 + 8].try_into().unwrap());
+    if field(8) != content_hash(&header[..64]) {
+        return Ok(None);
+    }
+    if field(1) != patch_id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "In-place journal belongs to a different patch",
+        ));
+    }
+    let meta = Meta {
+        patch_id,
+        old_len: field(2),
+        new_size: field(3),
+        chunk: field(4),
+        stash_len: field(5),
+        stash_hash: field(6),
+        rollback: field(7) != 0,
+    };
+    if meta.chunk == 0 || field(7) > 1 {
+        return Err(invalid("In-place journal is corrupt"));
+    }
+    Ok(Some(meta))
+}
+
+/// Read the journal left by an earlier call: its header, stash and latest slot
+fn read_journal<J: Storage>(journal: &mut J, patch_id: u64) -> io::Result<Option<Journaled>> {
+    let meta = match read_meta(journal, patch_id)? {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+
+    let mut stash = Vec::new();
+    try_reserve(&mut stash, to_usize(meta.stash_len)?)?;
+    stash.resize(meta.stash_len as usize, 0);
+    journal.seek(SeekFrom::Start(META_SIZE as u64))?;
+    if !read_full(journal, &mut stash)? || content_hash(&stash) != meta.stash_hash {
+        return Err(invalid("In-place journal is corrupt"));
+    }
+
+    let mut latest: Option<Slot> = None;
+    let mut log_end = meta.slots_start();
+    if meta.rollback {
+        log_end = read_log(journal, &meta, |slot| {
+            latest = Some(slot);
+            Ok(())
+        })?;
+    } else {
+        for area in 0..2 {
+            let offset = meta.slot_offset(area, 0);
+            if let Some(slot) = read_slot(journal, &meta, offset)?.filter(|s| s.seq % 2 == area) {
+                if latest.as_ref().map_or(true, |l| slot.seq > l.seq) {
+                    latest = Some(slot);
+                }
+            }
+        }
+    }
+    Ok(Some(Journaled {
+        meta,
+        stash,
+        slot: latest,
+        log_end,
+    }))
+}
+
+/// Pass each slot of a rollback journal to `f` in order, returning where the last
+/// one ends. The log ends at the first slot that is missing, torn or out of order.
+fn read_log<J, F>(journal: &mut J, meta: &Meta, mut f: F) -> io::Result<u64>
+where
+    J: Storage,
+    F: FnMut(Slot) -> io::Result<()>,
+{
+    let mut offset = meta.slots_start();
+    let mut seq = 0;
+    while let Some(slot) = read_slot(journal, meta, offset)?.filter(|s| s.seq == seq) {
+        offset += (SLOT_HEADER_SIZE + slot.undo.len()) as u64;
+        seq += 1;
+        f(slot)?;
+    }
+    Ok(offset)
+}
+
+/// Start a journal: the stash first, then the header that makes it valid
+fn write_journal<J: Storage>(journal: &mut J, meta: &Meta, stash: &[u8]) -> io::Result<()> {
+    journal.set_len(0)?;
+    write_at(journal, META_SIZE as u64, stash)?;
+    journal.sync()?;
+
+    let mut header = [0u8; META_SIZE];
+    let fields = [
+        meta.patch_id,
+        meta.old_len,
+        meta.new_size,
+        meta.chunk,
+        meta.stash_len,
+        meta.stash_hash,
+        meta.rollback as u64,
+    ];
+    header[..8].copy_from_slice(JOURNAL_MAGIC);
+    for (i, v) in fields.iter().enumerate() {
+        header[8 + i * 8..16 + i * 8].copy_from_slice(&v.to_le_bytes());
+    }
+    let checksum = content_hash(&header[..64]);
+    header[64..].copy_from_slice(&checksum.to_le_bytes());
+    write_at(journal, 0, &header)?;
+    journal.sync()
+}
+
+/// Slots alternate between two areas, or are appended to a rollback journal, so a
+/// torn write never loses the previous one. Returns where the slot ends.
+fn write_slot<J: Storage>(
+    journal: &mut J,
+    meta: &Meta,
+    slot: &Slot,
+    log_end: u64,
+) -> io::Result<u64> {
+    let mut record = Vec::with_capacity(SLOT_HEADER_SIZE + slot.undo.len());
+    for v in [
+        slot.seq,
+        slot.step,
+        slot.index,
+        slot.pos,
+        slot.undo.len() as u64,
+        0,
+    ] {
+        record.extend_from_slice(&v.to_le_bytes());
+    }
+    record.extend_from_slice(&slot.undo);
+    let checksum = content_hash(&record);
+    record[40..48].copy_from_slice(&checksum.to_le_bytes());
+
+    let offset = meta.slot_offset(slot.seq, log_end);
+    write_at(journal, offset, &record)?;
+    journal.sync()?;
+    Ok(offset + record.len() as u64)
+}
+
+/// Read the slot at `offset`, or `None` if it is missing or torn
+fn read_slot<J: Storage>(journal: &mut J, meta: &Meta, offset: u64) -> io::Result<Option<Slot>> {
+    let mut header = [0u8; SLOT_HEADER_SIZE];
+    journal.seek(SeekFrom::Start(offset))?;
+    if !read_full(journal, &mut header)? {
+        return Ok(None);
+    }
+    let field = |i: usize| u64::from_le_bytes(header[i * 8..i * 8 + 8].try_into().unwrap());
+    if field(4) > meta.chunk {
+        return Ok(None);
+    }
+
+    let mut record = header.to_vec();
+    record.resize(SLOT_HEADER_SIZE + field(4) as usize, 0);
+    if !read_full(journal, &mut record[SLOT_HEADER_SIZE..])? {
+        return Ok(None);
+    }
+    record[40..48].fill(0);
+    if content_hash(&record) != field(5) {
+        return Ok(None);
+    }
+    Ok(Some(Slot {
+        seq: field(0),
+        step: field(1),
+        index: field(2),
+        pos: field(3),
+        undo: record.split_off(SLOT_HEADER_SIZE),
+    }))
+}
+
+fn read_at<S: Read + Seek + ?Sized>(storage: &mut S, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+    storage.seek(SeekFrom::Start(pos))?;
+    storage.read_exact(buf)
+}
+
+fn write_at<S: Write + Seek + ?Sized>(storage: &mut S, pos: u64, data: &[u8]) -> io::Result<()> {
+    storage.seek(SeekFrom::Start(pos))?;
+    storage.write_all(data)
+}
+
+/// Like `read_exact`, but reports running out of data as `false`
+fn read_full<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn to_usize(value: u64) -> io::Result<usize> {
+    usize::try_from(value).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Size exceeds the address space of this platform",
+        )
+    })
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompressionAlgorithm as Alg;
+
+    /// Storage that loses power after a number of writes, leaving the last one torn
+    struct PowerCut<S> {
+        inner: S,
+        writes_left: usize,
+    }
+
+    impl<S: Read> Read for PowerCut<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl<S: Write> Write for PowerCut<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.writes_left == 0 {
+                self.inner.write_all(&buf[..buf.len() / 2])?;
+                return Err(io::Error::new(io::ErrorKind::Other, "power cut"));
+            }
+            self.writes_left -= 1;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl<S: Seek> Seek for PowerCut<S> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl<S: Storage> Storage for PowerCut<S> {
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.inner.set_len(len)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.inner.sync()
+        }
+    }
+
+    /// An old file whose new version swaps two large blocks, which forces a cycle
+    fn swapped_blocks(shrink: usize, grow: usize) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let old: Vec<u8> = (0..40_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old[20_000..].to_vec();
+        new.extend_from_slice(&old[..20_000 - shrink]);
+        new[12_345] ^= 0xFF;
+        new.extend((0..grow).map(|i| i as u8));
+
+        let mut patch = Vec::new();
+        crate::diff_bsdf2_uniform(&old, &new, &mut patch, Alg::Bz2).unwrap();
+        (old, new, patch)
+    }
+
+    #[test]
+    fn test_in_place_matches_patch_bsdf2() {
+        for (shrink, grow) in [(0, 0), (3_000, 0), (0, 5_000)] {
+            let (old, new, patch) = swapped_blocks(shrink, grow);
+            let mut target = Cursor::new(old.clone());
+            let mut journal = Cursor::new(Vec::new());
+            apply(
+                &mut target,
+                &patch,
+                &mut journal,
+                1 << 20,
+                4096,
+                &PatchOptions::default(),
+            )
+            .unwrap();
+            assert!(target.get_ref() == &new);
+
+            // A completed journal makes a second call a no-op
+            apply(
+                &mut target,
+                &patch,
+                &mut journal,
+                1 << 20,
+                4096,
+                &PatchOptions::default(),
+            )
+            .unwrap();
+            assert!(target.into_inner() == new);
+        }
+
+        // Swapping the blocks cannot be done without scratch
+        let (old, _, patch) = swapped_blocks(0, 0);
+        let err = patch_bsdf2_in_place(
+            &mut Cursor::new(old),
+            &patch,
+            &mut Cursor::new(Vec::new()),
+            0,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::LimitExceeded {
+                limit: Limit::Scratch,
+                max: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_in_place_resumes_after_power_cut() {
+        let (old, new, patch) = swapped_blocks(3_000, 2_000);
+        let options = PatchOptions::default();
+        let run = |target_writes: usize, journal_writes: usize| {
+            let mut target = PowerCut {
+                inner: Cursor::new(old.clone()),
+                writes_left: target_writes,
+            };
+            let mut journal = PowerCut {
+                inner: Cursor::new(Vec::new()),
+                writes_left: journal_writes,
+            };
+            let result = apply(&mut target, &patch, &mut journal, 1 << 20, 1024, &options);
+            (result, target, journal)
+        };
+
+        // Count the writes of an uninterrupted run, then cut power before each one
+        let (result, target, journal) = run(usize::MAX, usize::MAX);
+        result.unwrap();
+        let target_writes = usize::MAX - target.writes_left;
+        let journal_writes = usize::MAX - journal.writes_left;
+        let cuts = (0..target_writes)
+            .map(|n| (n, usize::MAX))
+            .chain((0..journal_writes).map(|n| (usize::MAX, n)));
+
+        for (target_writes, journal_writes) in cuts {
+            let (result, target, journal) = run(target_writes, journal_writes);
+            assert!(result.is_err());
+
+            let (mut target, mut journal) = (target.inner, journal.inner);
+            apply(&mut target, &patch, &mut journal, 1 << 20, 1024, &options).unwrap();
+            assert!(
+                target.into_inner() == new,
+                "cut after {} target / {} journal writes",
+                target_writes,
+                journal_writes
+            );
+        }
+    }
+
+    #[test]
+    fn test_in_place_rolls_back_to_old() {
+        for (shrink, grow) in [(3_000, 0), (0, 2_000)] {
+            let (old, new, patch) = swapped_blocks(shrink, grow);
+            let options = PatchOptions {
+                in_place_journal: JournalMode::Rollback {
+                    budget: old.len() as u64,
+                },
+                ..PatchOptions::default()
+            };
+            let run = |target_writes: usize| {
+                let mut target = PowerCut {
+                    inner: Cursor::new(old.clone()),
+                    writes_left: target_writes,
+                };
+                let mut journal = Cursor::new(Vec::new());
+                let result = apply(&mut target, &patch, &mut journal, 1 << 20, 1024, &options);
+                (result, target, journal)
+            };
+
+            // Roll back after every possible interruption, and after completion
+            let (result, target, _) = run(usize::MAX);
+            result.unwrap();
+            let writes = usize::MAX - target.writes_left;
+            for target_writes in (0..writes).chain([usize::MAX]) {
+                let (result, target, mut journal) = run(target_writes);
+                assert_eq!(result.is_ok(), target_writes == usize::MAX);
+                let mut target = target.inner;
+                if target_writes == writes / 2 {
+                    // A rollback journal resumes like any other
+                    let mut target = target.clone();
+                    let mut journal = journal.clone();
+                    apply(&mut target, &patch, &mut journal, 1 << 20, 1024, &options).unwrap();
+                    assert!(target.into_inner() == new);
+                }
+                rollback_bsdf2_in_place(&mut target, &patch, &mut journal).unwrap();
+                assert!(
+                    target.get_ref() == &old,
+                    "rolled back after {} target writes",
+                    target_writes
+                );
+
+                // The journal is reset, so the patch can be applied afresh
+                apply(&mut target, &patch, &mut journal, 1 << 20, 1024, &options).unwrap();
+                assert!(target.into_inner() == new);
+            }
+
+            // A journal kept for resuming only cannot roll back
+            let mut target = Cursor::new(old.clone());
+            let mut journal = Cursor::new(Vec::new());
+            patch_bsdf2_in_place(&mut target, &patch, &mut journal, 1 << 20).unwrap();
+            let err = rollback_bsdf2_in_place(&mut target, &patch, &mut journal).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+            // Too small a budget fails before anything is written
+            let options = PatchOptions {
+                in_place_journal: JournalMode::Rollback { budget: 1000 },
+                ..PatchOptions::default()
+            };
+            let mut target = Cursor::new(old.clone());
+            let err = patch_bsdf2_in_place_with_options(
+                &mut target,
+                &patch,
+                &mut Cursor::new(Vec::new()),
+                1 << 20,
+                &options,
+            )
+            .unwrap_err();
+            assert!(matches!(
+                crate::patch_error(&err),
+                Some(PatchError::LimitExceeded {
+                    limit: Limit::Journal,
+                    max: 1000,
+                    ..
+                })
+            ));
+            assert!(target.into_inner() == old);
+        }
+    }
+
+    #[test]
+    fn test_plan_breaks_cycles_with_smallest_read() {
+        // 0 reads what 1 writes and the other way round; 2 depends on neither
+        let reads = [10..30, 0..5, 40..50];
+        let writes = [0..10, 10..40, 40..50];
+        let plan = plan(&reads, &writes, 5).unwrap();
+        assert_eq!(plan.stashed, vec![1]);
+        assert_eq!(plan.order, vec![2, 0, 1]);
+        assert_eq!(super::plan(&reads, &writes, 4).unwrap_err(), 1);
+    }
+}
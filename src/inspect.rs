@@ -0,0 +1,295 @@
+// inspect.rs - Walking the control tuples of a patch for debugging
+
+use std::borrow::Cow;
+use std::io;
+
+use crate::bsdf2::{decompress, offtin, parse_with_limits};
+use crate::bsdf2_writer::{CompressionAlgorithm, ControlEntry};
+use crate::control::TupleValidator;
+use crate::error::{Limit, PatchError, Position, Stream};
+use crate::format::{detect_format, PatchFormat};
+use crate::patch::{OldBoundsMode, PatchLimits};
+
+/// One decoded control tuple and the data it uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchTuple<'a> {
+    /// Index of the tuple and the old/new offsets at which it starts
+    pub at: Position,
+    /// ADD length, COPY length and seek, as stored in the patch
+    pub entry: ControlEntry,
+    /// Bytes added to old, starting at `at.old_pos`, to produce the ADD output
+    pub diff: &'a [u8],
+    /// Bytes copied verbatim to the output after the ADD
+    pub extra: &'a [u8],
+}
+
+/// Decodes a raw, BSDIFF40, BSDF2 or ENDSLEY/BSDIFF43 patch for inspection.
+///
+/// The streams are decompressed up front (within `PatchLimits`); `tuples` then walks
+/// the control tuples with the running old/new offsets and the diff and extra bytes
+/// of each one. Nothing needs the old file.
+pub struct PatchReader<'p> {
+    format: PatchFormat,
+    new_size: Option<u64>,
+    control: Cow<'p, [u8]>,
+    diff: Vec<u8>,
+    extra: Vec<u8>,
+}
+
+impl<'p> PatchReader<'p> {
+    pub fn new(patch_data: &'p [u8]) -> io::Result<Self> {
+        Self::with_limits(patch_data, &PatchLimits::default())
+    }
+
+    /// Like `new`, with caller-chosen bounds on the decompressed streams
+    pub fn with_limits(patch_data: &'p [u8], limits: &PatchLimits) -> io::Result<Self> {
+        let format = detect_format(patch_data);
+        let reader = match format {
+            PatchFormat::Raw => Self {
+                format,
+                new_size: None,
+                control: Cow::Borrowed(patch_data),
+                diff: Vec::new(),
+                extra: Vec::new(),
+            },
+            PatchFormat::Bsdiff40 | PatchFormat::Bsdf2 => {
+                let (new_size, control, diff, extra) = parse_with_limits(patch_data, limits)?;
+                Self {
+                    format,
+                    new_size: Some(new_size as u64),
+                    control: Cow::Owned(control),
+                    diff,
+                    extra,
+                }
+            }
+            PatchFormat::Endsley => {
+                let size = patch_data.get(16..24).ok_or(PatchError::Truncated {
+                    at: Position::default(),
+                })?;
+                let new_size = offtin(size.try_into().unwrap());
+                if new_size < 0 {
+                    return Err(PatchError::NegativeLength {
+                        at: Position::default(),
+                        value: new_size,
+                    }
+                    .into());
+                }
+                let max = limits.max_stream_size;
+                let control = decompress(
+                    CompressionAlgorithm::Bz2,
+                    &patch_data[24..],
+                    max.saturating_add(1),
+                )?;
+                PatchLimits::check(
+                    Position::default(),
+                    Limit::StreamSize(Stream::Control),
+                    max,
+                    control.len() as u64,
+                )?;
+                Self {
+                    format,
+                    new_size: Some(new_size as u64),
+                    control: Cow::Owned(control),
+                    diff: Vec::new(),
+                    extra: Vec::new(),
+                }
+            }
+        };
+        Ok(reader)
+    }
+
+    pub fn format(&self) -> PatchFormat {
+        self.format
+    }
+
+    /// Size of the new file recorded in the header; `None` for raw patches
+    pub fn new_size(&self) -> Option<u64> {
+        self.new_size
+    }
+
+    /// Buffers holding the diff and extra bytes; the control stream itself for
+    /// formats that interleave them with the tuples
+    pub(crate) fn data(&self) -> (&[u8], &[u8]) {
+        if self.is_interleaved() {
+            (&self.control, &self.control)
+        } else {
+            (&self.diff, &self.extra)
+        }
+    }
+
+    pub(crate) fn is_interleaved(&self) -> bool {
+        matches!(self.format, PatchFormat::Raw | PatchFormat::Endsley)
+    }
+
+    /// Iterate the control tuples.
+    ///
+    /// A malformed tuple yields a `PatchError` (wrapped in `io::Error`) and ends the
+    /// iteration. So does leftover diff or extra data, or a new size that does not
+    /// match the header, once the last tuple has been returned.
+    pub fn tuples(&self) -> Tuples<'_> {
+        let interleaved = self.is_interleaved();
+        let mut validator = TupleValidator::new(self.new_size);
+        if !interleaved {
+            validator = validator.with_streams(self.diff.len() as u64, self.extra.len() as u64);
+        }
+        Tuples {
+            interleaved,
+            validator,
+            control: &self.control,
+            diff: &self.diff,
+            extra: &self.extra,
+            done: false,
+        }
+    }
+}
+
+/// Iterator over the control tuples of a `PatchReader`
+pub struct Tuples<'a> {
+    /// Diff and extra bytes follow each tuple in the control stream
+    interleaved: bool,
+    /// The same checks the patchers make; the old file is only checked if given
+    /// through `with_old_bounds`
+    validator: TupleValidator,
+    control: &'a [u8],
+    diff: &'a [u8],
+    extra: &'a [u8],
+    done: bool,
+}
+
+/// Split `len` bytes off the front of `buf`
+fn take<'a>(buf: &mut &'a [u8], len: u64) -> Option<&'a [u8]> {
+    let len = usize::try_from(len).ok().filter(|&len| len <= buf.len())?;
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Some(head)
+}
+
+impl<'a> Tuples<'a> {
+    /// Also check ADDs against the end of an old file of `old_len` bytes
+    pub(crate) fn with_old_bounds(mut self, old_len: u64, mode: OldBoundsMode) -> Self {
+        self.validator = self.validator.with_old_bounds(old_len, mode);
+        self
+    }
+
+    fn next_tuple(&mut self) -> Result<Option<PatchTuple<'a>>, PatchError> {
+        let at = self.validator.at();
+        if self.control.is_empty() {
+            return self.validator.finish().map(|_| None);
+        }
+
+        let exhausted = |stream| {
+            if self.interleaved {
+                PatchError::Truncated { at }
+            } else {
+                PatchError::StreamExhausted { at, stream }
+            }
+        };
+        let ctrl = take(&mut self.control, 24).ok_or_else(|| exhausted(Stream::Control))?;
+        let tuple = self.validator.next(ctrl.try_into().unwrap())?;
+        let (add_len, copy_len) = (tuple.add_len, tuple.copy_len);
+
+        // The validator has checked the lengths of separate streams, but interleaved
+        // data is only found to be missing here
+        let (diff, extra) = if self.interleaved {
+            let diff = take(&mut self.control, add_len).ok_or_else(|| exhausted(Stream::Diff))?;
+            let extra =
+                take(&mut self.control, copy_len).ok_or_else(|| exhausted(Stream::Extra))?;
+            (diff, extra)
+        } else {
+            let diff = take(&mut self.diff, add_len).ok_or_else(|| exhausted(Stream::Diff))?;
+            let extra = take(&mut self.extra, copy_len).ok_or_else(|| exhausted(Stream::Extra))?;
+            (diff, extra)
+        };
+
+        Ok(Some(PatchTuple {
+            at,
+            entry: ControlEntry {
+                diff_size: add_len as i64,
+                extra_size: copy_len as i64,
+                offset_increment: tuple.seek,
+            },
+            diff,
+            extra,
+        }))
+    }
+}
+
+impl<'a> Iterator for Tuples<'a> {
+    type Item = io::Result<PatchTuple<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_tuple() {
+            Ok(Some(tuple)) => Some(Ok(tuple)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rebuild the new file from the tuples alone
+    fn replay(old: &[u8], reader: &PatchReader<'_>) -> Vec<u8> {
+        let mut new = Vec::new();
+        for tuple in reader.tuples() {
+            let tuple = tuple.unwrap();
+            assert_eq!(tuple.at.new_pos, new.len() as u64);
+            for (i, d) in tuple.diff.iter().enumerate() {
+                let o = old.get(tuple.at.old_pos as usize + i).copied().unwrap_or(0);
+                new.push(o.wrapping_add(*d));
+            }
+            new.extend_from_slice(tuple.extra);
+        }
+        new
+    }
+
+    #[test]
+    fn test_tuples_rebuild_new_file() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * 13 % 251) as u8).collect();
+        let mut new = old[3000..].to_vec();
+        new[500..700].fill(1);
+        new.extend_from_slice(b"appended");
+
+        let mut raw = Vec::new();
+        crate::diff(&old, &new, &mut raw).unwrap();
+        let mut bsdf2 = Vec::new();
+        crate::diff_bsdf2_uniform(&old, &new, &mut bsdf2, CompressionAlgorithm::Brotli).unwrap();
+        let mut endsley = Vec::new();
+        crate::diff_endsley(&old, &new, &mut endsley).unwrap();
+
+        for (patch, format) in [
+            (&raw, PatchFormat::Raw),
+            (&bsdf2, PatchFormat::Bsdf2),
+            (&endsley, PatchFormat::Endsley),
+        ] {
+            let reader = PatchReader::new(patch).unwrap();
+            assert_eq!(reader.format(), format);
+            assert_eq!(replay(&old, &reader), new);
+        }
+    }
+
+    #[test]
+    fn test_truncated_raw_patch_ends_with_error() {
+        let mut raw = Vec::new();
+        crate::diff(b"abcdef", b"abcdefgh", &mut raw).unwrap();
+        raw.pop();
+
+        let reader = PatchReader::new(&raw).unwrap();
+        let last = reader.tuples().last().unwrap().unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&last),
+            Some(PatchError::Truncated { .. })
+        ));
+    }
+}
@@ -1,16 +1,67 @@
 #![allow(clippy::needless_doctest_main)]
 #![doc = include_str!("../README.md")]
 
-mod diff;
-mod patch;
 mod bsdf2;
+mod bsdf2_stream;
 mod bsdf2_writer;
+mod checkpoint;
+mod control;
+mod diff;
+mod endsley;
+mod error;
+mod extents;
+mod files;
+mod format;
+mod inplace;
+mod inspect;
+mod parallel;
+mod patch;
+mod patched_reader;
+mod progress;
+mod sink;
+mod stats;
+mod suffix;
 
-pub use diff::{diff, diff_bsdiff40, diff_bsdf2, diff_bsdf2_uniform};
-pub use patch::patch;
-pub use bsdf2::{patch_bsdf2, parse_bsdf2_header};
+// Not part of the public API; only exposed for benches/simd.rs
+#[cfg(feature = "bench-internals")]
+#[doc(hidden)]
+pub mod simd;
+#[cfg(not(feature = "bench-internals"))]
+mod simd;
 
-pub use bsdf2_writer::{CompressionAlgorithm, ControlEntry, Bsdf2Writer};
+pub use bsdf2::{parse_bsdf2_header, patch_bsdf2, patch_bsdf2_with_options, Bsdf2Header};
+pub use bsdf2_stream::{
+    patch_bsdf2_resumable, patch_bsdf2_stream, patch_bsdf2_stream_with_options,
+};
+pub use checkpoint::Checkpoint;
+pub use diff::{diff, diff_bsdf2, diff_bsdf2_uniform, diff_bsdiff40, diff_endsley, DiffIndex};
+pub use diff::{diff_bsdf2_auto, diff_bsdf2_auto_with_options};
+pub use diff::{
+    diff_bsdf2_with_options, diff_bsdiff40_with_options, diff_endsley_with_options, diff_to_sink,
+    diff_with_options, DiffOptions,
+};
+pub use endsley::{patch_endsley, patch_endsley_with_options, EndsleyWriter};
+pub use error::{file_error, patch_error, FileError, Limit, PatchError, Position, Stream};
+pub use extents::{parse_extents, patch_bsdf2_extents, patch_bsdf2_extents_with_options};
+pub use extents::{Extent, ExtentReader, ExtentWriter};
+pub use files::{diff_files, patch_files};
+pub use format::{apply_any_patch, detect_format, PatchFormat};
+pub use inplace::{
+    patch_bsdf2_in_place, patch_bsdf2_in_place_with_options, rollback_bsdf2_in_place, JournalMode,
+    Storage,
+};
+pub use inspect::{PatchReader, PatchTuple, Tuples};
+pub use parallel::{patch_bsdf2_parallel, patch_bsdf2_parallel_with_options};
+pub use patch::{patch, patch_with_options, try_patch, OldBoundsMode, PatchLimits, PatchOptions};
+pub use patched_reader::PatchedReader;
+pub use progress::{is_cancelled, CancelToken, Cancelled, Phase, Progress, ProgressCallback};
+pub use sink::{ControlSink, RawWriter};
+pub use stats::{
+    patch_stats, ExtraRegion, PatchStats, SeekBucket, StreamStats, LARGEST_EXTRA_REGIONS,
+};
+
+pub use bsdf2_writer::{Bsdf2Writer, CompressionAlgorithm, ControlEntry};
+pub use bsdf2_writer::{CompressionParams, BROTLI_DEFAULT_LGWIN, BROTLI_MAX_BUFFER_SIZE};
 
-pub use patch::patch as apply_patch;
 pub use bsdf2::patch_bsdf2 as apply_bsdf2_patch;
+pub use patch::patch as apply_patch;
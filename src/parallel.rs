@@ -0,0 +1,274 @@
+// parallel.rs - Multi-threaded BSDF2 patch application
+
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::bsdf2::parse_with_limits;
+use crate::control::TupleValidator;
+use crate::error::{PatchError, Stream};
+use crate::patch::{try_reserve, PatchOptions};
+use crate::simd;
+
+/// How much output a worker produces between progress updates and cancel checks
+const REPORT_INTERVAL: usize = 1 << 20;
+
+/// One control tuple with all of its ranges resolved
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Op {
+    pub(crate) new_start: usize,
+    pub(crate) old_start: usize,
+    pub(crate) diff_start: usize,
+    pub(crate) add_len: usize,
+    pub(crate) extra_start: usize,
+    pub(crate) copy_len: usize,
+}
+
+impl Op {
+    pub(crate) fn new_end(&self) -> usize {
+        self.new_start + self.add_len + self.copy_len
+    }
+}
+
+/// Apply a BSDF2 or BSDIFF40 patch using several threads.
+///
+/// The output is byte-identical to `patch_bsdf2`. `threads` of 0 uses all available
+/// cores.
+pub fn patch_bsdf2_parallel(
+    old: &[u8],
+    patch_data: &[u8],
+    new: &mut Vec<u8>,
+    threads: usize,
+) -> io::Result<()> {
+    patch_bsdf2_parallel_with_options(old, patch_data, new, threads, &PatchOptions::default())
+}
+
+/// Multi-threaded patch application with limits, progress reporting and cancellation.
+///
+/// The streams are decompressed and the control tuples are validated and resolved to
+/// their old, diff, extra and new ranges up front, on the calling thread. The new file
+/// is then split into equal byte ranges, one per thread, and each thread fills its
+/// range from whichever tuples overlap it, so a single huge tuple is shared too.
+/// Errors are the same as those of `patch_bsdf2`, and are reported before any
+/// output is produced.
+pub fn patch_bsdf2_parallel_with_options(
+    old: &[u8],
+    patch_data: &[u8],
+    new: &mut Vec<u8>,
+    threads: usize,
+    options: &PatchOptions,
+) -> io::Result<()> {
+    let (new_size, control, diff, extra) = parse_with_limits(patch_data, &options.limits)?;
+    let new_size = new_size as usize;
+    let ops = resolve_ops(old.len(), &control, &diff, &extra, new_size, options)?;
+
+    new.clear();
+    try_reserve(new, new_size)?;
+    new.resize(new_size, 0);
+
+    let threads = match threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .min(new_size / REPORT_INTERVAL + 1);
+
+    let reporter = Mutex::new((options.reporter(new_size as u64), 0u64));
+    let report = |done: usize| -> io::Result<()> {
+        let mut guard = reporter.lock().unwrap_or_else(|e| e.into_inner());
+        let (reporter, total) = &mut *guard;
+        *total += done as u64;
+        reporter.update(*total)
+    };
+
+    // Split the output into one contiguous range per thread
+    let mut ranges = Vec::with_capacity(threads);
+    let mut rest = &mut new[..];
+    let mut start = 0;
+    for i in 0..threads {
+        let end = new_size * (i + 1) / threads;
+        let (head, tail) = rest.split_at_mut(end - start);
+        ranges.push((start, head));
+        rest = tail;
+        start = end;
+    }
+
+    let (ops, diff, extra) = (&ops[..], &diff[..], &extra[..]);
+    let results: Vec<io::Result<()>> = thread::scope(|scope| {
+        let workers: Vec<_> = ranges
+            .into_iter()
+            .map(|(start, out)| {
+                scope.spawn(move || fill(old, diff, extra, ops, start, out, &report))
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    });
+    results.into_iter().collect::<io::Result<()>>()?;
+
+    let (reporter, _) = reporter.into_inner().unwrap_or_else(|e| e.into_inner());
+    reporter.report(new_size as u64);
+    Ok(())
+}
+
+/// Validate the control tuples through `TupleValidator`, like every other patcher,
+/// and resolve their ranges
+pub(crate) fn resolve_ops(
+    old_len: usize,
+    control: &[u8],
+    diff: &[u8],
+    extra: &[u8],
+    new_size: usize,
+    options: &PatchOptions,
+) -> io::Result<Vec<Op>> {
+    let mut ops = Vec::new();
+    ops.try_reserve(control.len() / 24)
+        .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+
+    let mut tuples = TupleValidator::new(Some(new_size as u64))
+        .with_streams(diff.len() as u64, extra.len() as u64)
+        .with_old_bounds(old_len as u64, options.old_bounds);
+    let mut chunks = control.chunks_exact(24);
+    for ctrl in &mut chunks {
+        let tuple = tuples.next(ctrl.try_into().unwrap())?;
+        // Offsets past the end of old saturate; they only ever read zeros
+        ops.push(Op {
+            new_start: tuple.at.new_pos as usize,
+            old_start: usize::try_from(tuple.at.old_pos).unwrap_or(usize::MAX),
+            diff_start: tuple.diff_pos as usize,
+            add_len: tuple.add_len as usize,
+            extra_start: tuple.extra_pos as usize,
+            copy_len: tuple.copy_len as usize,
+        });
+    }
+    if !chunks.remainder().is_empty() {
+        return Err(PatchError::StreamExhausted {
+            at: tuples.at(),
+            stream: Stream::Control,
+        }
+        .into());
+    }
+    tuples.finish()?;
+    Ok(ops)
+}
+
+/// Produce `out`, the bytes of the new file starting at offset `start`
+fn fill(
+    old: &[u8],
+    diff: &[u8],
+    extra: &[u8],
+    ops: &[Op],
+    start: usize,
+    out: &mut [u8],
+    report: &(dyn Fn(usize) -> io::Result<()> + Sync),
+) -> io::Result<()> {
+    let end = start + out.len();
+    let mut unreported = 0;
+    let first = ops.partition_point(|op| op.new_end() <= start);
+
+    for op in ops[first..].iter().take_while(|op| op.new_start < end) {
+        // ADD part: old plus diff, zero past the end of old
+        let add_end = op.new_start + op.add_len;
+        let (lo, hi) = (op.new_start.max(start), add_end.min(end));
+        if lo < hi {
+            let k = lo - op.new_start;
+            let out = &mut out[lo - start..hi - start];
+            out.copy_from_slice(&diff[op.diff_start + k..][..hi - lo]);
+            let old = op.old_start.checked_add(k).and_then(|p| old.get(p..));
+            simd::add_bytes(out, old.unwrap_or(&[]));
+        }
+
+        // COPY part: extra bytes verbatim
+        let (lo, hi) = (add_end.max(start), op.new_end().min(end));
+        if lo < hi {
+            let k = lo - add_end;
+            out[lo - start..hi - start].copy_from_slice(&extra[op.extra_start + k..][..hi - lo]);
+        }
+
+        unreported += op.new_end().min(end) - op.new_start.max(start);
+        if unreported >= REPORT_INTERVAL {
+            report(unreported)?;
+            unreported = 0;
+        }
+    }
+    report(unreported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsdf2_writer::{Bsdf2Writer, CompressionAlgorithm as Alg, ControlEntry};
+    use crate::patch::OldBoundsMode;
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let old: Vec<u8> = (0..3_000_000u32).map(|i| (i * 31 % 253) as u8).collect();
+        let mut new = old[10_000..].to_vec();
+        for i in (0..new.len()).step_by(40_000) {
+            new[i] = new[i].wrapping_add(1);
+        }
+        new.splice(1_500_000..1_500_000, (0..50_000u32).map(|i| (i % 7) as u8));
+
+        let mut patch = Vec::new();
+        crate::diff_bsdf2_uniform(&old, &new, &mut patch, Alg::None).unwrap();
+        let mut serial = Vec::new();
+        crate::patch_bsdf2(&old, &patch, &mut serial).unwrap();
+
+        for threads in [0, 1, 2, 3, 8] {
+            let mut out = vec![1, 2, 3];
+            patch_bsdf2_parallel(&old, &patch, &mut out, threads).unwrap();
+            assert!(out == serial, "{} threads", threads);
+        }
+    }
+
+    fn seek_patch(seeks: &[i64]) -> Vec<u8> {
+        let mut writer = Bsdf2Writer::new(Alg::None, Alg::None, Alg::None);
+        for &seek in seeks {
+            let entry = ControlEntry {
+                diff_size: 200,
+                extra_size: 3,
+                offset_increment: seek,
+            };
+            writer.add_control_entry(entry).unwrap();
+            writer.write_diff_stream(&[1; 200]).unwrap();
+            writer.write_extra_stream(&[9; 3]).unwrap();
+        }
+        let mut patch = Vec::new();
+        writer.close(&mut patch).unwrap();
+        patch
+    }
+
+    #[test]
+    fn test_parallel_zero_fill_and_errors_match_serial() {
+        let old = vec![5u8; 1000];
+
+        // The second ADD reads past the end of old and is zero-filled
+        let patch = seek_patch(&[900, -30]);
+        let mut serial = Vec::new();
+        crate::patch_bsdf2(&old, &patch, &mut serial).unwrap();
+        let mut parallel = Vec::new();
+        patch_bsdf2_parallel(&old, &patch, &mut parallel, 3).unwrap();
+        assert_eq!(parallel, serial);
+
+        let strict = PatchOptions {
+            old_bounds: OldBoundsMode::Strict,
+            ..PatchOptions::default()
+        };
+        let serial = crate::patch_bsdf2_with_options(&old, &patch, &mut Vec::new(), &strict);
+        let parallel = patch_bsdf2_parallel_with_options(&old, &patch, &mut Vec::new(), 3, &strict);
+        assert_eq!(
+            crate::patch_error(&parallel.unwrap_err()),
+            crate::patch_error(&serial.unwrap_err())
+        );
+
+        let patch = seek_patch(&[900, -30, -2000]);
+        let serial = crate::patch_bsdf2(&old, &patch, &mut Vec::new()).unwrap_err();
+        let parallel = patch_bsdf2_parallel(&old, &patch, &mut Vec::new(), 4).unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&serial),
+            Some(PatchError::SeekUnderflow { .. })
+        ));
+        assert_eq!(crate::patch_error(&parallel), crate::patch_error(&serial));
+    }
+}
@@ -32,6 +32,98 @@ use std::io;
 use std::io::Read;
 use std::ops::DerefMut;
 
+use crate::control::TupleValidator;
+use crate::error::{Limit, PatchError, Position};
+use crate::inplace::JournalMode;
+use crate::progress::{CancelToken, Phase, ProgressCallback, Reporter};
+use crate::simd;
+
+/// Upper bounds on what a patch may make the patcher allocate or produce.
+///
+/// Patches are untrusted input: a few kilobytes of compressed data can claim a huge
+/// new file or decompress into gigabytes. Exceeding a limit fails with
+/// `PatchError::LimitExceeded` before the memory is committed, or for the streaming
+/// patchers before the output or the decompressed streams grow past the limit.
+/// `patch`, `patch_bsdf2_stream` and `patch_bsdf2_extents` take no options and use
+/// `PatchLimits::unbounded()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchLimits {
+    /// Largest new file a patch may produce. Default: 2 GiB.
+    pub max_new_size: u64,
+    /// Largest decompressed control, diff or extra stream. Default: 2 GiB.
+    pub max_stream_size: u64,
+    /// Most control tuples a patch may contain. Default: 2 GiB worth of tuples.
+    pub max_control_tuples: u64,
+}
+
+impl Default for PatchLimits {
+    fn default() -> Self {
+        Self {
+            max_new_size: 2 << 30,
+            max_stream_size: 2 << 30,
+            max_control_tuples: (2 << 30) / 24,
+        }
+    }
+}
+
+impl PatchLimits {
+    /// No limits at all, for trusted patches or outputs that are not held in memory
+    pub fn unbounded() -> Self {
+        Self {
+            max_new_size: u64::MAX,
+            max_stream_size: u64::MAX,
+            max_control_tuples: u64::MAX,
+        }
+    }
+
+    /// Fail if `value` is above `max`
+    pub(crate) fn check(at: Position, limit: Limit, max: u64, value: u64) -> io::Result<()> {
+        if value > max {
+            return Err(PatchError::LimitExceeded { at, limit, max }.into());
+        }
+        Ok(())
+    }
+}
+
+/// Patch data is copied into the output at most this many bytes per reservation
+const COPY_CHUNK: usize = 1 << 20;
+
+/// What an ADD does when it reads past the end of the old file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OldBoundsMode {
+    /// Treat the missing old bytes as zero, like AOSP bspatch. This is the default.
+    #[default]
+    AospZeroFill,
+    /// Fail with `PatchError::OldOutOfBounds`
+    Strict,
+}
+
+/// Options shared by the patch functions
+#[derive(Debug, Clone, Default)]
+pub struct PatchOptions {
+    /// Receives `Phase::Patch` progress as the new file is produced. Default: none.
+    pub progress: Option<ProgressCallback>,
+    /// Aborts patching with a `Cancelled` error when triggered. Default: none.
+    pub cancel: Option<CancelToken>,
+    /// Bounds on the size of the output and of the decompressed streams
+    pub limits: PatchLimits,
+    /// How reads past the end of the old file are handled. Default: `AospZeroFill`.
+    pub old_bounds: OldBoundsMode,
+    /// What the journal of an in-place patch keeps. Default: `JournalMode::Resume`.
+    pub in_place_journal: JournalMode,
+}
+
+impl PatchOptions {
+    pub(crate) fn reporter(&self, total: u64) -> Reporter<'_> {
+        Reporter::new(
+            self.progress.as_ref(),
+            self.cancel.as_ref(),
+            Phase::Patch,
+            total,
+        )
+    }
+}
+
 /// Apply a patch to an "old" file, returning the "new" file.
 ///
 /// `old` is the old file, `patch` will be read from with the patch, `new` is the buffer that will be written into.
@@ -40,98 +132,130 @@ use std::ops::DerefMut;
 /// - Bulk read operations
 /// - SIMD-friendly memory access patterns
 /// - Proper validation with early errors
+///
+/// No `PatchLimits` apply; use `patch_with_options` to cap the output of untrusted
+/// patches.
 pub fn patch<T, W>(old: &[u8], patch: &mut T, new: &mut W) -> io::Result<()>
 where
     T: Read,
     W: io::Write + DerefMut<Target = [u8]>,
 {
-    let mut oldpos: usize = 0;
-    
-    loop {
-        // Read control data
-        let mut buf = [0; 24];
-        if read_or_eof(patch, &mut buf)? {
-            return Ok(());
-        }
-
-        // Decode using bspatch sign-magnitude encoding (NOT plain LE)
-        // This matches AOSP/bspatch behavior
-        let mix_len_raw = offtin(buf[0..8].try_into().unwrap());
-        let copy_len_raw = offtin(buf[8..16].try_into().unwrap());
-        let seek_len = offtin(buf[16..24].try_into().unwrap());
-
-        // Validate lengths are non-negative
-        if mix_len_raw < 0 || copy_len_raw < 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Negative length: mix={}, copy={}", mix_len_raw, copy_len_raw),
-            ));
-        }
-
-        let mix_len = mix_len_raw as usize;
-        let copy_len = copy_len_raw as usize;
-
-        // Check for overflow before reading
-        let to_read = mix_len
-            .checked_add(copy_len)
-            .ok_or(io::ErrorKind::InvalidData)?;
-
-        // Read diff string and literal data at once (bulk operation)
-        let mix_start = new.len();
-        let mut read_from = patch.take(to_read as u64);
-        let has_read = io::copy(&mut read_from, new)?;
+    let options = PatchOptions {
+        limits: PatchLimits::unbounded(),
+        ..PatchOptions::default()
+    };
+    patch_with_options(old, patch, new, &options)
+}
 
-        if has_read != to_read as u64 {
-            return Err(io::ErrorKind::UnexpectedEof.into());
-        }
+/// Apply a raw patch with progress reporting, cancellation and `PatchLimits`.
+///
+/// Raw patches do not record the new size, so progress is reported with a `total` of 0.
+pub fn patch_with_options<T, W>(
+    old: &[u8],
+    patch: &mut T,
+    new: &mut W,
+    options: &PatchOptions,
+) -> io::Result<()>
+where
+    T: Read,
+    W: io::Write + DerefMut<Target = [u8]>,
+{
+    patch_raw(old, patch, new, options, |_, _| Ok(()))
+}
 
-        // Compute mix range with overflow checks
-        let mix_end = mix_start
-            .checked_add(mix_len)
-            .ok_or(io::ErrorKind::InvalidData)?;
+/// Like `patch_with_options`, but grows `new` with `try_reserve`, so running out of
+/// memory is an `OutOfMemory` error instead of an abort
+pub fn try_patch<T: Read>(
+    old: &[u8],
+    patch: &mut T,
+    new: &mut Vec<u8>,
+    options: &PatchOptions,
+) -> io::Result<()> {
+    patch_raw(old, patch, new, options, try_reserve)
+}
 
-        let mix_slice = new
-            .get_mut(mix_start..mix_end)
-            .ok_or(io::ErrorKind::UnexpectedEof)?;
+/// The raw patch loop. `reserve` is called before each chunk of patch data is copied
+/// into `new`.
+fn patch_raw<T, W, R>(
+    old: &[u8],
+    patch: &mut T,
+    new: &mut W,
+    options: &PatchOptions,
+    mut reserve: R,
+) -> io::Result<()>
+where
+    T: Read,
+    W: io::Write + DerefMut<Target = [u8]>,
+    R: FnMut(&mut W, usize) -> io::Result<()>,
+{
+    let start = new.len();
+    let mut reporter = options.reporter(0);
 
-        // Compute old range with overflow checks
-        let oldpos_end = oldpos
-            .checked_add(mix_len)
-            .ok_or(io::ErrorKind::InvalidData)?;
+    let limits = &options.limits;
+    // Raw patches record no new size, and the data is checked as it is read
+    let mut tuples =
+        TupleValidator::new(None).with_old_bounds(old.len() as u64, options.old_bounds);
 
-        let old_slice = old
-            .get(oldpos..oldpos_end)
-            .ok_or(io::ErrorKind::UnexpectedEof)?;
+    loop {
+        reporter.update((new.len() - start) as u64)?;
+        let at = tuples.at();
 
-        // Mix operation: new[i] += old[i]
-        // This is optimized for SIMD and cache locality
-        for (n, o) in mix_slice.iter_mut().zip(old_slice.iter().copied()) {
-            *n = n.wrapping_add(o);
+        // Read control data
+        let mut buf = [0; 24];
+        let eof = read_or_eof(patch, &mut buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => PatchError::Truncated { at }.into(),
+            _ => e,
+        })?;
+        if eof {
+            break;
         }
+        PatchLimits::check(
+            at,
+            Limit::ControlTuples,
+            limits.max_control_tuples,
+            at.tuple + 1,
+        )?;
+
+        // Decode using bspatch sign-magnitude encoding (NOT plain LE), validate the
+        // lengths and the seek, and check where the tuple ends against the limit
+        let tuple = tuples.next(&buf)?;
+        PatchLimits::check(at, Limit::NewSize, limits.max_new_size, tuples.at().new_pos)?;
+        let mix_len = usize::try_from(tuple.add_len).map_err(|_| io::ErrorKind::InvalidData)?;
+        let to_read = usize::try_from(tuple.add_len + tuple.copy_len)
+            .map_err(|_| io::ErrorKind::InvalidData)?;
 
-        // Adjust oldpos with mix_len
-        oldpos += mix_len;
-
-        // Apply seek with proper validation
-        // CRITICAL FIX: Check for underflow before converting
-        let new_oldpos = (oldpos as i64)
-            .checked_add(seek_len)
-            .ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Seek overflow: oldpos={}, seek={}", oldpos, seek_len),
-                )
-            })?;
-
-        if new_oldpos < 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Seek underflow: oldpos={}, seek={}", oldpos, seek_len),
-            ));
+        // Read diff string and literal data in bulk, reserving room as it arrives so a
+        // truncated patch claiming a large tuple does not allocate all of it up front
+        let mix_start = new.len();
+        let mut remaining = to_read;
+        while remaining > 0 {
+            let chunk = remaining.min(COPY_CHUNK);
+            reserve(new, chunk)?;
+            let has_read = io::copy(&mut patch.take(chunk as u64), new)?;
+            if has_read != chunk as u64 {
+                return Err(PatchError::Truncated { at }.into());
+            }
+            remaining -= chunk;
         }
 
-        oldpos = new_oldpos as usize;
+        // Mix operation: new[i] += old[i]. Bytes past the end of old are left as they
+        // are, i.e. added to zero
+        let mix_slice = &mut new[mix_start..mix_start + mix_len];
+        let oldpos = usize::try_from(at.old_pos)
+            .unwrap_or(usize::MAX)
+            .min(old.len());
+        let old_slice = &old[oldpos..oldpos.saturating_add(mix_len).min(old.len())];
+        simd::add_bytes(mix_slice, old_slice);
     }
+
+    reporter.report((new.len() - start) as u64);
+    Ok(())
+}
+
+/// Reserve room for `additional` more bytes, failing instead of aborting on OOM
+pub(crate) fn try_reserve(buf: &mut Vec<u8>, additional: usize) -> io::Result<()> {
+    buf.try_reserve(additional)
+        .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))
 }
 
 /// It allows EOF only before the first byte.
@@ -146,7 +270,7 @@ fn read_or_eof<T: Read>(reader: &mut T, buf: &mut [u8; 24]) -> io::Result<bool>
                     Ok(true) // Clean EOF at start
                 } else {
                     Err(io::ErrorKind::UnexpectedEof.into())
-                }
+                };
             }
             Ok(n) => {
                 if n >= tmp.len() {
@@ -160,22 +284,10 @@ fn read_or_eof<T: Read>(reader: &mut T, buf: &mut [u8; 24]) -> io::Result<bool>
     }
 }
 
-/// Reads sign-magnitude i64 as used in bspatch
-/// This is the CORRECT encoding used by AOSP and classic bspatch
-/// NOT plain little-endian i64!
-#[inline]
-fn offtin(buf: [u8; 8]) -> i64 {
-    let y = i64::from_le_bytes(buf);
-    if 0 == y & (1 << 63) {
-        y
-    } else {
-        -(y & !(1 << 63))
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bsdf2::offtin;
 
     #[test]
     fn test_offtin_zero() {
@@ -209,4 +321,137 @@ mod tests {
         let buf = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
         assert_eq!(offtin(buf), -i64::MAX);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_wrong_old_file_is_reported() {
+        let old: Vec<u8> = (0..1000u32).map(|i| (i % 13) as u8).collect();
+        let mut raw = Vec::new();
+        crate::diff(&old, &old, &mut raw).unwrap();
+
+        let options = PatchOptions {
+            old_bounds: OldBoundsMode::Strict,
+            ..PatchOptions::default()
+        };
+        let mut out = Vec::new();
+        let err =
+            patch_with_options(&old[..500], &mut raw.as_slice(), &mut out, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        match crate::patch_error(&err) {
+            Some(PatchError::OldOutOfBounds { at, old_len, .. }) => {
+                assert_eq!((at.tuple, *old_len), (0, 500));
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_seek_past_end_of_old() {
+        use crate::bsdf2_writer::ControlEntry;
+        use crate::sink::{ControlSink, RawWriter};
+
+        // Skip past the end of old, then ADD four bytes from there
+        let old = [10u8, 20, 30, 40];
+        let mut raw = RawWriter::new(Vec::new());
+        raw.control(ControlEntry {
+            diff_size: 2,
+            extra_size: 0,
+            offset_increment: 100,
+        })
+        .unwrap();
+        raw.diff(&[1, 1]).unwrap();
+        raw.control(ControlEntry {
+            diff_size: 4,
+            extra_size: 0,
+            offset_increment: 0,
+        })
+        .unwrap();
+        raw.diff(&[1, 2, 3, 4]).unwrap();
+        let raw = raw.into_inner();
+
+        let mut out = Vec::new();
+        patch(&old, &mut raw.as_slice(), &mut out).unwrap();
+        assert_eq!(out, [11, 21, 1, 2, 3, 4]);
+
+        let options = PatchOptions {
+            old_bounds: OldBoundsMode::Strict,
+            ..PatchOptions::default()
+        };
+        let err =
+            patch_with_options(&old, &mut raw.as_slice(), &mut Vec::new(), &options).unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::OldOutOfBounds { at, .. }) if at.tuple == 1 && at.old_pos == 102
+        ));
+    }
+
+    #[test]
+    fn test_output_buffers_and_limits() {
+        use crate::bsdf2_writer::ControlEntry;
+        use crate::sink::{ControlSink, RawWriter};
+
+        let old = [0u8; 16];
+        let mut raw = RawWriter::new(Vec::new());
+        for byte in [1, 2] {
+            raw.control(ControlEntry {
+                diff_size: 8,
+                extra_size: 0,
+                offset_increment: 0,
+            })
+            .unwrap();
+            raw.diff(&[byte; 8]).unwrap();
+        }
+        let raw = raw.into_inner();
+        let expected = [[1; 8], [2; 8]].concat();
+
+        // Any growable byte buffer works as the output
+        let mut out = smallvec::SmallVec::<[u8; 4]>::new();
+        patch(&old, &mut raw.as_slice(), &mut out).unwrap();
+        assert_eq!(&out[..], &expected[..]);
+
+        // An explicit new size limit is checked before the tuple crossing it is copied
+        let options = PatchOptions {
+            limits: PatchLimits {
+                max_new_size: 12,
+                ..PatchLimits::default()
+            },
+            ..PatchOptions::default()
+        };
+        let mut out = Vec::new();
+        let err = patch_with_options(&old, &mut raw.as_slice(), &mut out, &options).unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::LimitExceeded {
+                limit: Limit::NewSize,
+                max: 12,
+                ..
+            })
+        ));
+        assert_eq!(out, [1; 8]);
+
+        let mut out = Vec::new();
+        try_patch(
+            &old,
+            &mut raw.as_slice(),
+            &mut out,
+            &PatchOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(out, expected);
+
+        // A tuple claiming far more than the patch holds fails without reserving it all
+        let mut huge = raw[..24].to_vec();
+        huge[..8].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        huge.extend_from_slice(&[0; 100]);
+        let options = PatchOptions {
+            limits: PatchLimits::unbounded(),
+            ..PatchOptions::default()
+        };
+        let mut out = Vec::new();
+        let err = try_patch(&old, &mut huge.as_slice(), &mut out, &options).unwrap_err();
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::Truncated { .. })
+        ));
+        assert!(out.capacity() <= 2 * COPY_CHUNK);
+    }
+}
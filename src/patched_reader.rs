@@ -0,0 +1,247 @@
+// patched_reader.rs - Random access to the new file without materializing it
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::control::add_signed;
+use crate::inspect::PatchReader;
+use crate::patch::PatchOptions;
+use crate::simd;
+
+/// Where one control tuple's output comes from
+struct Segment {
+    /// Offset of the tuple's first output byte in the new file
+    new_offset: u64,
+    add_len: u64,
+    copy_len: u64,
+    /// Read position in old for the ADD
+    old_offset: u64,
+    /// Offsets of the tuple's diff and extra bytes in `PatchReader::data`
+    diff_offset: usize,
+    extra_offset: usize,
+}
+
+/// Reads the new file a patch produces, computing only the bytes that are asked for.
+///
+/// The control tuples are indexed up front by new-file offset; each `read` then
+/// looks up the tuples covering the requested range and combines old with the diff
+/// and extra data for just those bytes. The patch streams are kept decompressed in
+/// memory, the new file is never built. Works with raw, BSDIFF40, BSDF2 and
+/// ENDSLEY/BSDIFF43 patches.
+pub struct PatchedReader<'a> {
+    old: &'a [u8],
+    patch: PatchReader<'a>,
+    segments: Vec<Segment>,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a> PatchedReader<'a> {
+    pub fn new(old: &'a [u8], patch_data: &'a [u8]) -> io::Result<Self> {
+        Self::with_options(old, patch_data, &PatchOptions::default())
+    }
+
+    /// Like `new`, applying the limits and old-bounds mode of `options`.
+    ///
+    /// The whole patch is validated here, so reads only fail on I/O-level misuse.
+    pub fn with_options(
+        old: &'a [u8],
+        patch_data: &'a [u8],
+        options: &PatchOptions,
+    ) -> io::Result<Self> {
+        let patch = PatchReader::with_limits(patch_data, &options.limits)?;
+        let interleaved = patch.is_interleaved();
+
+        let mut segments = Vec::new();
+        let (mut diff_offset, mut extra_offset) = (0usize, 0usize);
+        let mut len = 0u64;
+        let tuples = patch
+            .tuples()
+            .with_old_bounds(old.len() as u64, options.old_bounds);
+        for tuple in tuples {
+            let tuple = tuple?;
+            let (add_len, copy_len) = (tuple.diff.len(), tuple.extra.len());
+
+            if interleaved {
+                // Each tuple's diff and extra bytes follow its 24 control bytes
+                diff_offset = extra_offset + 24;
+                extra_offset = diff_offset + add_len;
+            }
+            if add_len + copy_len > 0 {
+                segments.push(Segment {
+                    new_offset: tuple.at.new_pos,
+                    add_len: add_len as u64,
+                    copy_len: copy_len as u64,
+                    old_offset: tuple.at.old_pos,
+                    diff_offset,
+                    extra_offset,
+                });
+            }
+            if interleaved {
+                extra_offset += copy_len;
+            } else {
+                diff_offset += add_len;
+                extra_offset += copy_len;
+            }
+            len = tuple.at.new_pos + (add_len + copy_len) as u64;
+        }
+
+        Ok(Self {
+            old,
+            patch,
+            segments,
+            len,
+            pos: 0,
+        })
+    }
+
+    /// Size of the new file
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Fill `buf` with new-file bytes starting at `offset`, returning how many were produced
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        let (diff, extra) = self.patch.data();
+        let mut filled = 0;
+        // Last segment starting at or before the offset
+        let mut index = self
+            .segments
+            .partition_point(|s| s.new_offset <= offset)
+            .saturating_sub(1);
+
+        while filled < buf.len() {
+            let seg = match self.segments.get(index) {
+                Some(seg) => seg,
+                None => break,
+            };
+            let pos = offset + filled as u64;
+            let rel = pos - seg.new_offset;
+            if rel >= seg.add_len + seg.copy_len {
+                index += 1;
+                continue;
+            }
+
+            let out = &mut buf[filled..];
+            let n = if rel < seg.add_len {
+                // ADD: old plus diff, zero past the end of old
+                let n = out.len().min((seg.add_len - rel) as usize);
+                let d = &diff[seg.diff_offset + rel as usize..][..n];
+                let old_start = seg.old_offset.saturating_add(rel);
+                out[..n].copy_from_slice(d);
+                let old = usize::try_from(old_start)
+                    .ok()
+                    .and_then(|p| self.old.get(p..));
+                simd::add_bytes(&mut out[..n], old.unwrap_or(&[]));
+                n
+            } else {
+                // COPY: extra bytes verbatim
+                let rel = (rel - seg.add_len) as usize;
+                let n = out.len().min(seg.copy_len as usize - rel);
+                out[..n].copy_from_slice(&extra[seg.extra_offset + rel..][..n]);
+                n
+            };
+            filled += n;
+        }
+        filled
+    }
+}
+
+impl Read for PatchedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read_at(self.pos, buf);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for PatchedReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => add_signed(self.len, d),
+            SeekFrom::Current(d) => add_signed(self.pos, d),
+        };
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompressionAlgorithm;
+
+    #[test]
+    fn test_random_access_matches_patch() {
+        let old: Vec<u8> = (0..40_000u32).map(|i| (i * 29 % 251) as u8).collect();
+        let mut new = old[1000..30_000].to_vec();
+        new[2000..2100].fill(5);
+        new.splice(9000..9000, (0..777u32).map(|i| (i % 3) as u8));
+        new.extend_from_slice(&old[..4000]);
+
+        let mut raw = Vec::new();
+        crate::diff(&old, &new, &mut raw).unwrap();
+        let mut bsdf2 = Vec::new();
+        crate::diff_bsdf2_uniform(&old, &new, &mut bsdf2, CompressionAlgorithm::Brotli).unwrap();
+
+        for patch in [&raw, &bsdf2] {
+            let mut reader = PatchedReader::new(&old, patch).unwrap();
+            assert_eq!(reader.len(), new.len() as u64);
+
+            for &(start, len) in &[(0, 10), (1999, 300), (8990, 1000), (new.len() - 5, 100)] {
+                reader.seek(SeekFrom::Start(start as u64)).unwrap();
+                let mut buf = vec![0u8; len];
+                let n = reader.read(&mut buf).unwrap();
+                let end = (start + len).min(new.len());
+                assert_eq!(&buf[..n], &new[start..end]);
+            }
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut all = Vec::new();
+            reader.read_to_end(&mut all).unwrap();
+            assert_eq!(all, new);
+            assert!(reader
+                .seek(SeekFrom::Current(-1 - new.len() as i64))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_strict_old_bounds() {
+        use crate::error::PatchError;
+        use crate::patch::OldBoundsMode;
+
+        let old: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let mut patch = Vec::new();
+        crate::diff(&old, &old, &mut patch).unwrap();
+
+        // Against a shorter old file the ADD reads past its end, which gives zeros
+        let short = &old[..4000];
+        let mut reader = PatchedReader::new(short, &patch).unwrap();
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all.len(), old.len());
+        assert!(all[4000..].iter().all(|&b| b == 0));
+
+        let options = PatchOptions {
+            old_bounds: OldBoundsMode::Strict,
+            ..PatchOptions::default()
+        };
+        let err = PatchedReader::with_options(short, &patch, &options)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            crate::patch_error(&err),
+            Some(PatchError::OldOutOfBounds { old_len: 4000, .. })
+        ));
+    }
+}
@@ -0,0 +1,185 @@
+// progress.rs - Progress reporting and cancellation for long diffs and patches
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Which part of the work a `Progress` update refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Building the suffix array of the old file; `total` is its length
+    SuffixSort,
+    /// Scanning the new file for matches; `total` is its length
+    Scan,
+    /// Producing the new file from a patch; `total` is the expected new size,
+    /// or 0 for raw patches, which do not record it
+    Patch,
+}
+
+/// A progress update: `done` out of `total` bytes in `phase`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    pub done: u64,
+    pub total: u64,
+}
+
+/// Callback receiving `Progress` updates
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(Progress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new<F: Fn(Progress) + Send + Sync + 'static>(f: F) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Shared flag used to abort a running diff or patch from another thread.
+///
+/// Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every operation holding this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Error payload of an operation stopped through its `CancelToken`.
+///
+/// It is returned wrapped in an `io::Error` of kind `Other`; use `is_cancelled` to
+/// recognize it. The kind is deliberately not `Interrupted`: `read_exact`,
+/// `write_all` and `io::copy` retry on `Interrupted`, so a cancellation passing
+/// through a caller's reader or writer would be retried instead of stopping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Operation cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+impl From<Cancelled> for io::Error {
+    fn from(c: Cancelled) -> Self {
+        io::Error::new(io::ErrorKind::Other, c)
+    }
+}
+
+/// Returns true if `err` was caused by a `CancelToken`
+pub fn is_cancelled(err: &io::Error) -> bool {
+    err.get_ref().map_or(false, |e| e.is::<Cancelled>())
+}
+
+/// Throttles progress callbacks and polls the cancel token for one phase
+pub(crate) struct Reporter<'a> {
+    progress: Option<&'a ProgressCallback>,
+    cancel: Option<&'a CancelToken>,
+    phase: Phase,
+    total: u64,
+    step: u64,
+    next: u64,
+}
+
+impl<'a> Reporter<'a> {
+    pub(crate) fn new(
+        progress: Option<&'a ProgressCallback>,
+        cancel: Option<&'a CancelToken>,
+        phase: Phase,
+        total: u64,
+    ) -> Self {
+        Self {
+            progress,
+            cancel,
+            phase,
+            total,
+            // Roughly a thousand updates per phase, but not more often than every 64 KiB
+            step: (total / 1024).max(64 * 1024),
+            next: 0,
+        }
+    }
+
+    /// Fail with `Cancelled` if the token has been triggered
+    #[inline]
+    pub(crate) fn check(&self) -> io::Result<()> {
+        match self.cancel {
+            Some(token) if token.is_cancelled() => Err(Cancelled.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record that `done` bytes are finished, reporting it if enough has changed
+    #[inline]
+    pub(crate) fn update(&mut self, done: u64) -> io::Result<()> {
+        self.check()?;
+        if done >= self.next {
+            self.next = done.saturating_add(self.step);
+            self.report(done);
+        }
+        Ok(())
+    }
+
+    /// Report `done` unconditionally, e.g. at the end of the phase
+    pub(crate) fn report(&self, done: u64) {
+        if let Some(callback) = self.progress {
+            (callback.0)(Progress {
+                phase: self.phase,
+                done,
+                total: self.total,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_cancelled_error_is_recognized() {
+        let err: io::Error = Cancelled.into();
+        assert!(is_cancelled(&err));
+        assert!(!is_cancelled(&io::Error::new(
+            io::ErrorKind::Other,
+            "other"
+        )));
+    }
+
+    #[test]
+    fn test_reporter_throttles_and_cancels() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        let callback = ProgressCallback::new(move |p| sink.lock().unwrap().push(p.done));
+        let token = CancelToken::new();
+
+        let mut reporter = Reporter::new(Some(&callback), Some(&token), Phase::Scan, 1 << 20);
+        for done in (0..1 << 20).step_by(1024) {
+            reporter.update(done).unwrap();
+        }
+        assert_eq!(seen.lock().unwrap().len(), 16);
+
+        token.cancel();
+        assert!(is_cancelled(&reporter.update(1 << 20).unwrap_err()));
+    }
+}
@@ -0,0 +1,249 @@
+// simd.rs - Vectorized kernels for the ADD loop and suffix matching
+//
+// Each kernel picks AVX2 or SSE2 on x86_64 and NEON on aarch64 at runtime, and
+// falls back to a portable scalar loop elsewhere. The `*_scalar` versions are
+// public so the benches can compare against them.
+
+use std::cmp::Ordering;
+
+/// Inputs shorter than this skip the feature check and go straight to the scalar loop
+const SIMD_MIN_LEN: usize = 32;
+
+/// `dst[i] = dst[i].wrapping_add(src[i])` over the length both slices have in common
+#[inline]
+pub fn add_bytes(dst: &mut [u8], src: &[u8]) {
+    let n = dst.len().min(src.len());
+    let (dst, src) = (&mut dst[..n], &src[..n]);
+    if n < SIMD_MIN_LEN {
+        return add_bytes_scalar(dst, src);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 support was just checked
+            return unsafe { x86::add_bytes_avx2(dst, src) };
+        }
+        // SAFETY: SSE2 is part of the x86_64 baseline
+        unsafe { x86::add_bytes_sse2(dst, src) }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // SAFETY: NEON support was just checked
+            return unsafe { neon::add_bytes(dst, src) };
+        }
+        add_bytes_scalar(dst, src)
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    add_bytes_scalar(dst, src)
+}
+
+/// Portable version of `add_bytes`
+pub fn add_bytes_scalar(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d = d.wrapping_add(*s);
+    }
+}
+
+/// Length of the common prefix of `a` and `b`
+#[inline]
+pub fn matchlen(a: &[u8], b: &[u8]) -> usize {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    // Most calls from the suffix search end within a few bytes; settle those
+    // without paying for the feature check
+    if n < SIMD_MIN_LEN || a[..8] != b[..8] {
+        return matchlen_scalar(a, b);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 support was just checked
+            return unsafe { x86::matchlen_avx2(a, b) };
+        }
+        // SAFETY: SSE2 is part of the x86_64 baseline
+        unsafe { x86::matchlen_sse2(a, b) }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // SAFETY: NEON support was just checked
+            return unsafe { neon::matchlen(a, b) };
+        }
+        matchlen_scalar(a, b)
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    matchlen_scalar(a, b)
+}
+
+/// Portable version of `matchlen`, eight bytes at a time
+pub fn matchlen_scalar(a: &[u8], b: &[u8]) -> usize {
+    let n = a.len().min(b.len());
+    let mut i = 0;
+    while i + 8 <= n {
+        let x = u64::from_le_bytes(a[i..i + 8].try_into().unwrap());
+        let y = u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        if x != y {
+            return i + ((x ^ y).trailing_zeros() / 8) as usize;
+        }
+        i += 8;
+    }
+    i + a[i..n]
+        .iter()
+        .zip(&b[i..n])
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+/// Lexicographic comparison of the first `min(a.len(), b.len())` bytes of `a` and `b`
+#[inline]
+pub fn compare_prefix(a: &[u8], b: &[u8]) -> Ordering {
+    let n = a.len().min(b.len());
+    match matchlen(a, b) {
+        m if m == n => Ordering::Equal,
+        m => a[m].cmp(&b[m]),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn add_bytes_avx2(dst: &mut [u8], src: &[u8]) {
+        let n = dst.len().min(src.len());
+        let mut i = 0;
+        while i + 32 <= n {
+            let d = _mm256_loadu_si256(dst.as_ptr().add(i) as *const __m256i);
+            let s = _mm256_loadu_si256(src.as_ptr().add(i) as *const __m256i);
+            _mm256_storeu_si256(
+                dst.as_mut_ptr().add(i) as *mut __m256i,
+                _mm256_add_epi8(d, s),
+            );
+            i += 32;
+        }
+        super::add_bytes_scalar(&mut dst[i..n], &src[i..n]);
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn add_bytes_sse2(dst: &mut [u8], src: &[u8]) {
+        let n = dst.len().min(src.len());
+        let mut i = 0;
+        while i + 16 <= n {
+            let d = _mm_loadu_si128(dst.as_ptr().add(i) as *const __m128i);
+            let s = _mm_loadu_si128(src.as_ptr().add(i) as *const __m128i);
+            _mm_storeu_si128(dst.as_mut_ptr().add(i) as *mut __m128i, _mm_add_epi8(d, s));
+            i += 16;
+        }
+        super::add_bytes_scalar(&mut dst[i..n], &src[i..n]);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn matchlen_avx2(a: &[u8], b: &[u8]) -> usize {
+        let n = a.len().min(b.len());
+        let mut i = 0;
+        while i + 32 <= n {
+            let x = _mm256_loadu_si256(a.as_ptr().add(i) as *const __m256i);
+            let y = _mm256_loadu_si256(b.as_ptr().add(i) as *const __m256i);
+            let eq = _mm256_movemask_epi8(_mm256_cmpeq_epi8(x, y)) as u32;
+            if eq != u32::MAX {
+                return i + (!eq).trailing_zeros() as usize;
+            }
+            i += 32;
+        }
+        i + super::matchlen_scalar(&a[i..n], &b[i..n])
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn matchlen_sse2(a: &[u8], b: &[u8]) -> usize {
+        let n = a.len().min(b.len());
+        let mut i = 0;
+        while i + 16 <= n {
+            let x = _mm_loadu_si128(a.as_ptr().add(i) as *const __m128i);
+            let y = _mm_loadu_si128(b.as_ptr().add(i) as *const __m128i);
+            let eq = _mm_movemask_epi8(_mm_cmpeq_epi8(x, y)) as u32;
+            if eq != 0xFFFF {
+                return i + (!eq).trailing_zeros() as usize;
+            }
+            i += 16;
+        }
+        i + super::matchlen_scalar(&a[i..n], &b[i..n])
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn add_bytes(dst: &mut [u8], src: &[u8]) {
+        let n = dst.len().min(src.len());
+        let mut i = 0;
+        while i + 16 <= n {
+            let d = vld1q_u8(dst.as_ptr().add(i));
+            let s = vld1q_u8(src.as_ptr().add(i));
+            vst1q_u8(dst.as_mut_ptr().add(i), vaddq_u8(d, s));
+            i += 16;
+        }
+        super::add_bytes_scalar(&mut dst[i..n], &src[i..n]);
+    }
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn matchlen(a: &[u8], b: &[u8]) -> usize {
+        let n = a.len().min(b.len());
+        let mut i = 0;
+        while i + 16 <= n {
+            let x = vld1q_u8(a.as_ptr().add(i));
+            let y = vld1q_u8(b.as_ptr().add(i));
+            if vminvq_u8(vceqq_u8(x, y)) != u8::MAX {
+                return i + super::matchlen_scalar(&a[i..i + 16], &b[i..i + 16]);
+            }
+            i += 16;
+        }
+        i + super::matchlen_scalar(&a[i..n], &b[i..n])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_kernels_match_scalar() {
+        let a = pseudo_random(300, 1);
+        let b = pseudo_random(300, 2);
+        for len in [0, 1, 15, 16, 17, 31, 32, 33, 63, 64, 100, 299] {
+            for offset in [0, 1, 7] {
+                let mut simd = a[offset..].to_vec();
+                let mut scalar = simd.clone();
+                add_bytes(&mut simd, &b[..len]);
+                add_bytes_scalar(&mut scalar, &b[..len]);
+                assert_eq!(simd, scalar, "len {} offset {}", len, offset);
+            }
+
+            for mismatch in (0..len).step_by(5).chain([len]) {
+                let x = a[..len].to_vec();
+                let mut y = x.clone();
+                if mismatch < len {
+                    y[mismatch] ^= 0x80;
+                }
+                assert_eq!(matchlen(&x, &y), mismatch);
+                assert_eq!(matchlen_scalar(&x, &y), mismatch);
+                assert_eq!(compare_prefix(&x, &y), x.cmp(&y));
+            }
+        }
+        assert_eq!(matchlen(&a, &a[..40]), 40);
+        assert_eq!(compare_prefix(&a[..40], &a), Ordering::Equal);
+    }
+}
@@ -0,0 +1,115 @@
+// sink.rs - Destinations for the output of the diff scan loop
+
+use std::io::{self, Write};
+
+use crate::bsdf2_writer::{encode_int64, ControlEntry};
+
+/// Receives the output of the bsdiff scan loop.
+///
+/// For every control entry the scan calls `control`, then `diff` with exactly
+/// `entry.diff_size` bytes, then `extra` with exactly `entry.extra_size` bytes.
+/// Implement this to write patches in a container format of your own and pass it
+/// to `diff_to_sink` or `DiffIndex::diff_to_sink`.
+pub trait ControlSink {
+    /// Receive the next control entry
+    fn control(&mut self, entry: ControlEntry) -> io::Result<()>;
+
+    /// Receive the diff bytes (new minus old) of the last control entry
+    fn diff(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Receive the extra bytes (copied verbatim from new) of the last control entry
+    fn extra(&mut self, bytes: &[u8]) -> io::Result<()>;
+}
+
+/// Writes the headerless, uncompressed patch format read by `patch()`
+pub struct RawWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> RawWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> ControlSink for RawWriter<W> {
+    fn control(&mut self, entry: ControlEntry) -> io::Result<()> {
+        let mut buf = [0u8; 24];
+        encode_int64(entry.diff_size, &mut buf[0..8]);
+        encode_int64(entry.extra_size, &mut buf[8..16]);
+        encode_int64(entry.offset_increment, &mut buf[16..24]);
+        self.inner.write_all(&buf)
+    }
+
+    fn diff(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)
+    }
+
+    fn extra(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the calls it receives
+    #[derive(Default)]
+    struct Recorder {
+        entries: Vec<ControlEntry>,
+        diff_len: usize,
+        extra_len: usize,
+    }
+
+    impl ControlSink for Recorder {
+        fn control(&mut self, entry: ControlEntry) -> io::Result<()> {
+            self.entries.push(entry);
+            Ok(())
+        }
+
+        fn diff(&mut self, bytes: &[u8]) -> io::Result<()> {
+            assert_eq!(bytes.len() as i64, self.entries.last().unwrap().diff_size);
+            self.diff_len += bytes.len();
+            Ok(())
+        }
+
+        fn extra(&mut self, bytes: &[u8]) -> io::Result<()> {
+            assert_eq!(bytes.len() as i64, self.entries.last().unwrap().extra_size);
+            self.extra_len += bytes.len();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_sink_sees_whole_new_file() {
+        let old: Vec<u8> = (0..3000u32).map(|i| (i % 200) as u8).collect();
+        let mut new = old.clone();
+        new.splice(1000..1000, b"inserted".iter().copied());
+
+        let mut recorder = Recorder::default();
+        crate::diff_to_sink(&old, &new, &mut recorder, &Default::default()).unwrap();
+        assert_eq!(recorder.diff_len + recorder.extra_len, new.len());
+    }
+
+    #[test]
+    fn test_raw_writer_matches_diff() {
+        let old = b"hello old world".to_vec();
+        let new = b"hello new world!".to_vec();
+
+        let mut expected = Vec::new();
+        crate::diff(&old, &new, &mut expected).unwrap();
+
+        let mut raw = RawWriter::new(Vec::new());
+        crate::diff_to_sink(&old, &new, &mut raw, &Default::default()).unwrap();
+        assert_eq!(raw.into_inner(), expected);
+    }
+}
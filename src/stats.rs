@@ -0,0 +1,320 @@
+// stats.rs - Size breakdown of a patch for release reviews
+
+use std::fmt::Write as _;
+use std::io;
+
+use crate::bsdf2::Bsdf2Header;
+use crate::format::PatchFormat;
+use crate::inspect::PatchReader;
+
+/// How many extra-data regions `PatchStats::largest_extra` keeps
+pub const LARGEST_EXTRA_REGIONS: usize = 10;
+
+/// Size of one patch stream before and after decompression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamStats {
+    /// Bytes the stream takes in the patch; `None` for ENDSLEY/BSDIFF43 patches,
+    /// which compress all streams together
+    pub compressed: Option<u64>,
+    /// Bytes after decompression
+    pub uncompressed: u64,
+}
+
+/// Seeks whose distance falls in `min..=max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeekBucket {
+    pub min: i64,
+    pub max: i64,
+    pub count: u64,
+}
+
+/// A run of extra data, i.e. new bytes that did not come from old
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtraRegion {
+    pub new_offset: u64,
+    pub len: u64,
+}
+
+/// Where the bytes of a patch go, as computed by `patch_stats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchStats {
+    pub format: PatchFormat,
+    /// Size of the new file the patch produces
+    pub new_size: u64,
+    pub control_entries: u64,
+    /// Bytes produced by ADD (old plus diff)
+    pub add_bytes: u64,
+    /// Bytes produced by COPY (extra data)
+    pub copy_bytes: u64,
+    /// ADD bytes whose diff byte is zero, i.e. copied unchanged from old. When old is
+    /// given, bytes past its end are zero-filled rather than copied and do not count.
+    pub exact_copy_bytes: u64,
+    pub control: StreamStats,
+    pub diff: StreamStats,
+    pub extra: StreamStats,
+    /// Seek distances in power-of-two buckets, ordered from most negative to most positive
+    pub seek_histogram: Vec<SeekBucket>,
+    /// The largest extra-data regions, largest first
+    pub largest_extra: Vec<ExtraRegion>,
+    /// ADD bytes that read past the end of old and were zero-filled; only computed
+    /// when old is given
+    pub zero_filled_bytes: Option<u64>,
+}
+
+impl PatchStats {
+    /// Fraction of the new file copied unchanged from old, between 0 and 1
+    pub fn exact_copy_fraction(&self) -> f64 {
+        if self.new_size == 0 {
+            return 0.0;
+        }
+        self.exact_copy_bytes as f64 / self.new_size as f64
+    }
+
+    /// Render the report as a JSON object
+    pub fn to_json(&self) -> String {
+        let stream = |s: &StreamStats| {
+            let compressed = s.compressed.map_or("null".to_string(), |c| c.to_string());
+            format!(
+                "{{\"compressed\":{},\"uncompressed\":{}}}",
+                compressed, s.uncompressed
+            )
+        };
+
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            "{{\"format\":\"{:?}\",\"new_size\":{},\"control_entries\":{},\"add_bytes\":{},\
+             \"copy_bytes\":{},\"exact_copy_bytes\":{},\"exact_copy_fraction\":{},",
+            self.format,
+            self.new_size,
+            self.control_entries,
+            self.add_bytes,
+            self.copy_bytes,
+            self.exact_copy_bytes,
+            self.exact_copy_fraction(),
+        );
+        let _ = write!(
+            json,
+            "\"streams\":{{\"control\":{},\"diff\":{},\"extra\":{}}},",
+            stream(&self.control),
+            stream(&self.diff),
+            stream(&self.extra),
+        );
+
+        let buckets: Vec<String> = self
+            .seek_histogram
+            .iter()
+            .map(|b| {
+                format!(
+                    "{{\"min\":{},\"max\":{},\"count\":{}}}",
+                    b.min, b.max, b.count
+                )
+            })
+            .collect();
+        let regions: Vec<String> = self
+            .largest_extra
+            .iter()
+            .map(|r| format!("{{\"new_offset\":{},\"len\":{}}}", r.new_offset, r.len))
+            .collect();
+        let zero_filled = self
+            .zero_filled_bytes
+            .map_or("null".to_string(), |z| z.to_string());
+        let _ = write!(
+            json,
+            "\"seek_histogram\":[{}],\"largest_extra\":[{}],\"zero_filled_bytes\":{}}}",
+            buckets.join(","),
+            regions.join(","),
+            zero_filled,
+        );
+        json
+    }
+}
+
+/// Bucket of a seek distance: 0, then ±1, ±2..3, ±4..7 and so on
+fn seek_bucket(seek: i64) -> (i64, i64) {
+    if seek == 0 {
+        return (0, 0);
+    }
+    let magnitude = seek.unsigned_abs();
+    let low = 1u64 << (63 - magnitude.leading_zeros());
+    let high = (low - 1).saturating_add(low).min(i64::MAX as u64);
+    let (low, high) = (low.min(i64::MAX as u64) as i64, high as i64);
+    if seek > 0 {
+        (low, high)
+    } else {
+        (-high, -low)
+    }
+}
+
+/// Compute size statistics for a raw, BSDIFF40, BSDF2 or ENDSLEY/BSDIFF43 patch.
+///
+/// `old` is optional; when given, the report also counts ADD bytes that read past
+/// its end.
+pub fn patch_stats(patch_data: &[u8], old: Option<&[u8]>) -> io::Result<PatchStats> {
+    let reader = PatchReader::new(patch_data)?;
+
+    let mut control_entries = 0u64;
+    let mut add_bytes = 0u64;
+    let mut copy_bytes = 0u64;
+    let mut exact_copy_bytes = 0u64;
+    let mut zero_filled = 0u64;
+    let mut seeks: Vec<(i64, i64, u64)> = Vec::new();
+    let mut extra_regions = Vec::new();
+
+    for tuple in reader.tuples() {
+        let tuple = tuple?;
+        control_entries += 1;
+        add_bytes += tuple.diff.len() as u64;
+        copy_bytes += tuple.extra.len() as u64;
+
+        // Only ADD bytes that read from old can be copies of it
+        let mut copied = tuple.diff;
+        if let Some(old) = old {
+            let end = tuple.at.old_pos.saturating_add(tuple.diff.len() as u64);
+            zero_filled += end - end.min(old.len() as u64).max(tuple.at.old_pos);
+            let in_old = (old.len() as u64).saturating_sub(tuple.at.old_pos);
+            let in_old = usize::try_from(in_old).unwrap_or(usize::MAX);
+            copied = &copied[..copied.len().min(in_old)];
+        }
+        exact_copy_bytes += copied.iter().filter(|&&b| b == 0).count() as u64;
+
+        let (min, max) = seek_bucket(tuple.entry.offset_increment);
+        match seeks.iter_mut().find(|b| b.0 == min) {
+            Some(bucket) => bucket.2 += 1,
+            None => seeks.push((min, max, 1)),
+        }
+
+        if !tuple.extra.is_empty() {
+            extra_regions.push(ExtraRegion {
+                new_offset: tuple.at.new_pos + tuple.diff.len() as u64,
+                len: tuple.extra.len() as u64,
+            });
+        }
+    }
+
+    seeks.sort_unstable();
+    extra_regions.sort_by(|a, b| b.len.cmp(&a.len).then(a.new_offset.cmp(&b.new_offset)));
+    extra_regions.truncate(LARGEST_EXTRA_REGIONS);
+
+    // Compressed sizes are only known separately for the three-stream formats
+    let control_len = control_entries * 24;
+    let (control, diff, extra) = match reader.format() {
+        PatchFormat::Bsdiff40 | PatchFormat::Bsdf2 => {
+            let header = Bsdf2Header::parse(patch_data)?;
+            let extra_len = (patch_data.len() as u64)
+                .saturating_sub(Bsdf2Header::SIZE as u64 + header.ctrl_len + header.diff_len);
+            (
+                Some(header.ctrl_len),
+                Some(header.diff_len),
+                Some(extra_len),
+            )
+        }
+        PatchFormat::Raw => (Some(control_len), Some(add_bytes), Some(copy_bytes)),
+        PatchFormat::Endsley => (None, None, None),
+    };
+
+    Ok(PatchStats {
+        format: reader.format(),
+        new_size: add_bytes + copy_bytes,
+        control_entries,
+        add_bytes,
+        copy_bytes,
+        exact_copy_bytes,
+        control: StreamStats {
+            compressed: control,
+            uncompressed: control_len,
+        },
+        diff: StreamStats {
+            compressed: diff,
+            uncompressed: add_bytes,
+        },
+        extra: StreamStats {
+            compressed: extra,
+            uncompressed: copy_bytes,
+        },
+        seek_histogram: seeks
+            .into_iter()
+            .map(|(min, max, count)| SeekBucket { min, max, count })
+            .collect(),
+        largest_extra: extra_regions,
+        zero_filled_bytes: old.map(|_| zero_filled),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seek_buckets() {
+        assert_eq!(seek_bucket(0), (0, 0));
+        assert_eq!(seek_bucket(1), (1, 1));
+        assert_eq!(seek_bucket(5), (4, 7));
+        assert_eq!(seek_bucket(-8), (-15, -8));
+        assert_eq!(seek_bucket(i64::MAX), (1 << 62, i64::MAX));
+        assert_eq!(seek_bucket(-i64::MAX), (-i64::MAX, -(1 << 62)));
+    }
+
+    #[test]
+    fn test_patch_stats() {
+        let old: Vec<u8> = (0..30_000u32).map(|i| (i * 17 % 253) as u8).collect();
+        let mut new = old.clone();
+        new[1000..1100].fill(3);
+        new.splice(20_000..20_000, (0..500u32).map(|i| (i * i % 7) as u8));
+
+        let mut patch = Vec::new();
+        crate::diff_bsdiff40(&old, &new, &mut patch).unwrap();
+        let stats = patch_stats(&patch, Some(&old)).unwrap();
+
+        assert_eq!(stats.format, PatchFormat::Bsdiff40);
+        assert_eq!(stats.new_size, new.len() as u64);
+        assert_eq!(stats.add_bytes + stats.copy_bytes, new.len() as u64);
+        assert!(stats.exact_copy_fraction() > 0.9);
+        assert_eq!(
+            stats.seek_histogram.iter().map(|b| b.count).sum::<u64>(),
+            stats.control_entries
+        );
+        assert_eq!(stats.zero_filled_bytes, Some(0));
+        let compressed = [stats.control, stats.diff, stats.extra]
+            .iter()
+            .map(|s| s.compressed.unwrap())
+            .sum::<u64>();
+        assert_eq!(compressed + 32, patch.len() as u64);
+
+        let json = stats.to_json();
+        assert!(json.starts_with("{\"format\":\"Bsdiff40\","));
+        assert!(json.contains(&format!("\"new_size\":{}", new.len())));
+        assert!(json.ends_with("\"zero_filled_bytes\":0}"));
+    }
+
+    #[test]
+    fn test_zero_filled_bytes_are_not_copies() {
+        use crate::bsdf2_writer::{Bsdf2Writer, CompressionAlgorithm as Alg, ControlEntry};
+
+        // Six unchanged ADD bytes starting two bytes before the end of old
+        let old = [5u8; 10];
+        let mut writer = Bsdf2Writer::new(Alg::None, Alg::None, Alg::None);
+        writer
+            .add_control_entry(ControlEntry {
+                diff_size: 0,
+                extra_size: 0,
+                offset_increment: 8,
+            })
+            .unwrap();
+        writer
+            .add_control_entry(ControlEntry {
+                diff_size: 6,
+                extra_size: 0,
+                offset_increment: 0,
+            })
+            .unwrap();
+        writer.write_diff_stream(&[0; 6]).unwrap();
+        let mut patch = Vec::new();
+        writer.close(&mut patch).unwrap();
+
+        let stats = patch_stats(&patch, Some(&old)).unwrap();
+        assert_eq!(stats.add_bytes, 6);
+        assert_eq!(stats.exact_copy_bytes, 2);
+        assert_eq!(stats.zero_filled_bytes, Some(4));
+    }
+}
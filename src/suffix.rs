@@ -0,0 +1,628 @@
+// suffix.rs - Linear-time suffix array construction (SA-IS)
+//
+// Based on Ge Nong, Sen Zhang and Wai Hong Chan, "Two Efficient Algorithms for
+// Linear Time Suffix Array Construction" (2009). The output has the same layout
+// the old qsufsort produced: `old.len() + 1` entries with the empty suffix first.
+//
+// Cancellation is checked between passes at every level of the recursion. Progress
+// is only reported for the passes over the whole input, as a share of its length.
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::progress::Reporter;
+use crate::simd::{compare_prefix, matchlen};
+
+/// Magic of a saved suffix array file
+const INDEX_MAGIC: &[u8; 8] = b"BSDIDX01";
+
+/// Size of the saved file header; keeps the entries 8-byte aligned in a mapping
+const INDEX_HEADER_SIZE: usize = 32;
+
+/// Integer type used for suffix array entries
+pub(crate) trait SaIndex: Copy + Eq + Send + Sync + 'static {
+    /// Marks a slot that has not been filled yet
+    const EMPTY: Self;
+
+    fn from_usize(i: usize) -> Self;
+    fn to_usize(self) -> usize;
+}
+
+impl SaIndex for u32 {
+    const EMPTY: Self = u32::MAX;
+
+    #[inline(always)]
+    fn from_usize(i: usize) -> Self {
+        debug_assert!(i < u32::MAX as usize);
+        i as u32
+    }
+
+    #[inline(always)]
+    fn to_usize(self) -> usize {
+        self as usize
+    }
+}
+
+impl SaIndex for u64 {
+    const EMPTY: Self = u64::MAX;
+
+    #[inline(always)]
+    fn from_usize(i: usize) -> Self {
+        i as u64
+    }
+
+    #[inline(always)]
+    fn to_usize(self) -> usize {
+        self as usize
+    }
+}
+
+/// Suffix array of `old`, using 32-bit entries whenever they are wide enough
+pub(crate) enum SuffixArray {
+    U32(Vec<u32>),
+    U64(Vec<u64>),
+    /// A saved index mapped from disk, with entries of `width` bytes after the header
+    Mapped {
+        map: Mmap,
+        width: usize,
+    },
+}
+
+impl SuffixArray {
+    /// Sort the suffixes of `old`, reporting each pass to `reporter` and stopping if
+    /// it is cancelled
+    pub(crate) fn new(old: &[u8], reporter: &mut Reporter) -> io::Result<Self> {
+        // Entries go up to old.len(), and u32::MAX is reserved as the empty marker
+        Ok(if old.len() < u32::MAX as usize - 1 {
+            SuffixArray::U32(build(old, reporter)?)
+        } else {
+            SuffixArray::U64(build(old, reporter)?)
+        })
+    }
+
+    /// Find the longest match for a prefix of `new` in `old`, returning its position and length
+    #[inline]
+    pub(crate) fn search(&self, old: &[u8], new: &[u8]) -> (usize, usize) {
+        match self {
+            SuffixArray::U32(sa) => search(sa, old, new),
+            SuffixArray::U64(sa) => search(sa, old, new),
+            SuffixArray::Mapped { map, width: 4 } => search(entries::<u32>(map), old, new),
+            SuffixArray::Mapped { map, .. } => search(entries::<u64>(map), old, new),
+        }
+    }
+
+    /// Serialize the index, recording the length and hash of the old file it belongs to
+    pub(crate) fn write_to<W: Write>(&self, old: &[u8], writer: &mut W) -> io::Result<()> {
+        let width = match self {
+            SuffixArray::U32(_) => 4,
+            SuffixArray::U64(_) => 8,
+            SuffixArray::Mapped { width, .. } => *width,
+        };
+
+        let mut header = [0u8; INDEX_HEADER_SIZE];
+        header[0..8].copy_from_slice(INDEX_MAGIC);
+        header[8] = width as u8;
+        header[16..24].copy_from_slice(&(old.len() as u64).to_le_bytes());
+        header[24..32].copy_from_slice(&content_hash(old).to_le_bytes());
+        writer.write_all(&header)?;
+
+        match self {
+            SuffixArray::U32(sa) => {
+                for chunk in sa.chunks(16 * 1024) {
+                    let bytes: Vec<u8> = chunk.iter().flat_map(|x| x.to_le_bytes()).collect();
+                    writer.write_all(&bytes)?;
+                }
+            }
+            SuffixArray::U64(sa) => {
+                for chunk in sa.chunks(8 * 1024) {
+                    let bytes: Vec<u8> = chunk.iter().flat_map(|x| x.to_le_bytes()).collect();
+                    writer.write_all(&bytes)?;
+                }
+            }
+            SuffixArray::Mapped { map, .. } => writer.write_all(&map[INDEX_HEADER_SIZE..])?,
+        }
+        Ok(())
+    }
+
+    /// Map a saved index, checking that it was built from exactly these `old` bytes
+    pub(crate) fn map_file(old: &[u8], path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is read-only. As with any mapped file, the caller must
+        // not truncate or rewrite the index file while it is in use.
+        let map = unsafe { Mmap::map(&file)? };
+
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if map.len() < INDEX_HEADER_SIZE || &map[0..8] != INDEX_MAGIC {
+            return Err(invalid("Not a suffix array index file"));
+        }
+
+        let width = map[8] as usize;
+        if width != 4 && width != 8 {
+            return Err(invalid("Unsupported suffix array entry width"));
+        }
+
+        let old_len = u64::from_le_bytes(map[16..24].try_into().unwrap());
+        let hash = u64::from_le_bytes(map[24..32].try_into().unwrap());
+        if old_len != old.len() as u64 || hash != content_hash(old) {
+            return Err(invalid(
+                "Suffix array index was built from a different old file",
+            ));
+        }
+
+        let expected = (old.len() + 1)
+            .checked_mul(width)
+            .and_then(|n| n.checked_add(INDEX_HEADER_SIZE));
+        if expected != Some(map.len()) {
+            return Err(invalid("Suffix array index has the wrong size"));
+        }
+
+        // The hash only covers old, so a damaged body must not send `search` out of bounds
+        let data = &map[INDEX_HEADER_SIZE..];
+        let in_bounds = if width == 4 {
+            data.chunks_exact(4)
+                .all(|c| u32::from_le_bytes(c.try_into().unwrap()) as u64 <= old_len)
+        } else {
+            data.chunks_exact(8)
+                .all(|c| u64::from_le_bytes(c.try_into().unwrap()) <= old_len)
+        };
+        if !in_bounds {
+            return Err(invalid("Suffix array index is corrupt"));
+        }
+
+        if cfg!(target_endian = "little") {
+            Ok(SuffixArray::Mapped { map, width })
+        } else {
+            let data = &map[INDEX_HEADER_SIZE..];
+            Ok(if width == 4 {
+                SuffixArray::U32(
+                    data.chunks_exact(4)
+                        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+                        .collect(),
+                )
+            } else {
+                SuffixArray::U64(
+                    data.chunks_exact(8)
+                        .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+                        .collect(),
+                )
+            })
+        }
+    }
+}
+
+/// View the entries of a mapped little-endian index
+fn entries<I: SaIndex>(map: &Mmap) -> &[I] {
+    let data = &map[INDEX_HEADER_SIZE..];
+    let len = data.len() / std::mem::size_of::<I>();
+    assert_eq!(data.as_ptr() as usize % std::mem::align_of::<I>(), 0);
+    // SAFETY: the mapping starts on a page boundary and the header keeps the entries
+    // aligned (checked above); `I` is u32 or u64, valid for any bit pattern, and
+    // `map_file` only produces this variant on little-endian targets.
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const I, len) }
+}
+
+/// 64-bit hash of the old file, stored in saved indexes to catch mismatched inputs.
+///
+/// This is not a cryptographic hash. The format is fixed, so the value must never
+/// change between releases.
+pub(crate) fn content_hash(data: &[u8]) -> u64 {
+    const K: u64 = 0x9E37_79B9_7F4A_7C15;
+
+    let mut h = K ^ data.len() as u64;
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        let w = u64::from_le_bytes(word.try_into().unwrap());
+        h = (h ^ w).wrapping_mul(K).rotate_left(29);
+    }
+    for &b in words.remainder() {
+        h = (h ^ b as u64).wrapping_mul(K).rotate_left(29);
+    }
+
+    // splitmix64 finalizer
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^ (h >> 31)
+}
+
+fn search<I: SaIndex>(mut sa: &[I], old: &[u8], new: &[u8]) -> (usize, usize) {
+    while sa.len() >= 3 {
+        let mid = (sa.len() - 1) / 2;
+        let left = &old[sa[mid].to_usize()..];
+
+        if compare_prefix(left, new) == Ordering::Less {
+            sa = &sa[mid..];
+        } else {
+            sa = &sa[..=mid];
+        }
+    }
+
+    let first = sa[0].to_usize();
+    let last = sa[sa.len() - 1].to_usize();
+    let x = matchlen(&old[first..], new);
+    let y = matchlen(&old[last..], new);
+    if x > y {
+        (first, x)
+    } else {
+        (last, y)
+    }
+}
+
+/// A string over an integer alphabet whose last symbol is a unique 0 sentinel
+trait Text {
+    fn len(&self) -> usize;
+    fn at(&self, i: usize) -> usize;
+}
+
+/// The input bytes shifted up by one, with a virtual sentinel appended
+struct Bytes<'a>(&'a [u8]);
+
+impl Text for Bytes<'_> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.0.len() + 1
+    }
+
+    #[inline(always)]
+    fn at(&self, i: usize) -> usize {
+        match self.0.get(i) {
+            Some(&b) => b as usize + 1,
+            None => 0,
+        }
+    }
+}
+
+/// The reduced string of LMS-substring names used in the recursion
+struct Names<'a, I>(&'a [I]);
+
+impl<I: SaIndex> Text for Names<'_, I> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline(always)]
+    fn at(&self, i: usize) -> usize {
+        self.0[i].to_usize()
+    }
+}
+
+fn build<I: SaIndex>(old: &[u8], reporter: &mut Reporter) -> io::Result<Vec<I>> {
+    let text = Bytes(old);
+    let mut sa = vec![I::EMPTY; text.len()];
+    sais(&text, &mut sa, 257, &mut Passes::new(reporter, old.len()))?;
+    Ok(sa)
+}
+
+/// Passes made over the whole input by `sais`; the recursion works on a shorter string
+const TOP_LEVEL_PASSES: u64 = 5;
+
+/// Checks for cancellation after each pass of `sais`, and reports the top level ones
+struct Passes<'r, 'a> {
+    reporter: &'r mut Reporter<'a>,
+    len: u64,
+    done: u64,
+    depth: usize,
+}
+
+impl<'r, 'a> Passes<'r, 'a> {
+    fn new(reporter: &'r mut Reporter<'a>, len: usize) -> Self {
+        Self {
+            reporter,
+            len: len as u64,
+            done: 0,
+            depth: 0,
+        }
+    }
+
+    /// The caller reports the end of the last pass along with the rest of the phase
+    fn finished(&mut self) -> io::Result<()> {
+        if self.depth == 0 {
+            self.done += 1;
+        }
+        if self.depth > 0 || self.done == TOP_LEVEL_PASSES {
+            return self.reporter.check();
+        }
+        self.reporter
+            .update(self.len * self.done / TOP_LEVEL_PASSES)
+    }
+}
+
+/// S/L suffix types, one bit each (set = S-type)
+struct Types(Vec<u64>);
+
+impl Types {
+    fn classify<T: Text>(s: &T) -> Self {
+        let n = s.len();
+        let mut types = Types(vec![0; (n + 63) / 64]);
+        types.set(n - 1);
+        let mut next_is_s = true;
+        for i in (0..n - 1).rev() {
+            let (a, b) = (s.at(i), s.at(i + 1));
+            let is_s = a < b || (a == b && next_is_s);
+            if is_s {
+                types.set(i);
+            }
+            next_is_s = is_s;
+        }
+        types
+    }
+
+    #[inline(always)]
+    fn set(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+
+    #[inline(always)]
+    fn is_s(&self, i: usize) -> bool {
+        self.0[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    #[inline(always)]
+    fn is_lms(&self, i: usize) -> bool {
+        i > 0 && self.is_s(i) && !self.is_s(i - 1)
+    }
+}
+
+/// How many times each symbol occurs in `s`
+fn count_symbols<T: Text>(s: &T, k: usize) -> Vec<usize> {
+    let mut counts = vec![0usize; k];
+    for i in 0..s.len() {
+        counts[s.at(i)] += 1;
+    }
+    counts
+}
+
+/// Fill `bkt` with the start (or end) offset of each symbol's bucket
+fn buckets<I: SaIndex>(counts: &[usize], bkt: &mut [I], end: bool) {
+    let mut sum = 0;
+    for (b, &count) in bkt.iter_mut().zip(counts) {
+        sum += count;
+        *b = I::from_usize(if end { sum } else { sum - count });
+    }
+}
+
+fn induce_l<T: Text, I: SaIndex>(
+    types: &Types,
+    sa: &mut [I],
+    s: &T,
+    counts: &[usize],
+    bkt: &mut [I],
+) {
+    buckets(counts, bkt, false);
+    for i in 0..sa.len() {
+        let j = sa[i];
+        if j == I::EMPTY || j.to_usize() == 0 {
+            continue;
+        }
+        let j = j.to_usize() - 1;
+        if !types.is_s(j) {
+            let c = s.at(j);
+            let slot = bkt[c].to_usize();
+            sa[slot] = I::from_usize(j);
+            bkt[c] = I::from_usize(slot + 1);
+        }
+    }
+}
+
+fn induce_s<T: Text, I: SaIndex>(
+    types: &Types,
+    sa: &mut [I],
+    s: &T,
+    counts: &[usize],
+    bkt: &mut [I],
+) {
+    buckets(counts, bkt, true);
+    for i in (0..sa.len()).rev() {
+        let j = sa[i];
+        if j == I::EMPTY || j.to_usize() == 0 {
+            continue;
+        }
+        let j = j.to_usize() - 1;
+        if types.is_s(j) {
+            let c = s.at(j);
+            let slot = bkt[c].to_usize() - 1;
+            sa[slot] = I::from_usize(j);
+            bkt[c] = I::from_usize(slot);
+        }
+    }
+}
+
+/// Returns true if the LMS substrings starting at `a` and `b` differ
+fn lms_substrings_differ<T: Text>(s: &T, types: &Types, a: usize, b: usize) -> bool {
+    let mut d = 0;
+    loop {
+        let (x, y) = (a + d, b + d);
+        if s.at(x) != s.at(y) || types.is_s(x) != types.is_s(y) {
+            return true;
+        }
+        if d > 0 && (types.is_lms(x) || types.is_lms(y)) {
+            return false;
+        }
+        d += 1;
+    }
+}
+
+/// Name the sorted LMS substrings in `sa[..n1]`, storing the name of the one at `pos`
+/// in `sa[n1 + pos / 2]`; no two LMS positions are adjacent, so that slot is unique.
+/// Returns the number of distinct names.
+fn name_lms<T: Text, I: SaIndex>(types: &Types, sa: &mut [I], s: &T, n1: usize) -> usize {
+    sa[n1..].iter_mut().for_each(|x| *x = I::EMPTY);
+    let mut name = 0;
+    let mut prev = None;
+    for i in 0..n1 {
+        let pos = sa[i].to_usize();
+        let differs = prev.map_or(true, |prev| lms_substrings_differ(s, types, pos, prev));
+        if differs {
+            name += 1;
+            prev = Some(pos);
+        }
+        sa[n1 + pos / 2] = I::from_usize(name - 1);
+    }
+    name
+}
+
+/// Sort the suffixes of `s` (symbols in `0..k`) into `sa`
+fn sais<T: Text, I: SaIndex>(s: &T, sa: &mut [I], k: usize, passes: &mut Passes) -> io::Result<()> {
+    let n = s.len();
+    if n == 1 {
+        sa[0] = I::from_usize(0);
+        return Ok(());
+    }
+
+    let types = Types::classify(s);
+    let counts = count_symbols(s, k);
+    let mut bkt = vec![I::from_usize(0); k];
+
+    // Stage 1: sort the LMS substrings by inducing from their bucket ends
+    buckets(&counts, &mut bkt, true);
+    sa.iter_mut().for_each(|x| *x = I::EMPTY);
+    for i in 1..n {
+        if types.is_lms(i) {
+            let c = s.at(i);
+            let slot = bkt[c].to_usize() - 1;
+            sa[slot] = I::from_usize(i);
+            bkt[c] = I::from_usize(slot);
+        }
+    }
+    induce_l(&types, sa, s, &counts, &mut bkt);
+    passes.finished()?;
+    induce_s(&types, sa, s, &counts, &mut bkt);
+    passes.finished()?;
+
+    // Move the sorted LMS positions to the front
+    let mut n1 = 0;
+    for i in 0..n {
+        let p = sa[i].to_usize();
+        if types.is_lms(p) {
+            sa[n1] = I::from_usize(p);
+            n1 += 1;
+        }
+    }
+
+    let name = name_lms(&types, sa, s, n1);
+    passes.finished()?;
+    let mut j = n;
+    for i in (n1..n).rev() {
+        if sa[i] != I::EMPTY {
+            j -= 1;
+            sa[j] = sa[i];
+        }
+    }
+
+    // Stage 2: sort the reduced string, recursing only if the names are not unique
+    {
+        let (head, tail) = sa.split_at_mut(n - n1);
+        let sa1 = &mut head[..n1];
+        if name < n1 {
+            passes.depth += 1;
+            let sorted = sais(&Names(tail), sa1, name, passes);
+            passes.depth -= 1;
+            sorted?;
+        } else {
+            for (i, c) in tail.iter().enumerate() {
+                sa1[c.to_usize()] = I::from_usize(i);
+            }
+        }
+    }
+
+    // Stage 3: induce the full suffix array from the sorted LMS suffixes
+    buckets(&counts, &mut bkt, true);
+    {
+        let (head, tail) = sa.split_at_mut(n - n1);
+        let mut j = 0;
+        for i in 1..n {
+            if types.is_lms(i) {
+                tail[j] = I::from_usize(i);
+                j += 1;
+            }
+        }
+        for x in head[..n1].iter_mut() {
+            *x = tail[x.to_usize()];
+        }
+    }
+    sa[n1..].iter_mut().for_each(|x| *x = I::EMPTY);
+    for i in (0..n1).rev() {
+        let j = sa[i].to_usize();
+        sa[i] = I::EMPTY;
+        let c = s.at(j);
+        let slot = bkt[c].to_usize() - 1;
+        sa[slot] = I::from_usize(j);
+        bkt[c] = I::from_usize(slot);
+    }
+    induce_l(&types, sa, s, &counts, &mut bkt);
+    passes.finished()?;
+    induce_s(&types, sa, s, &counts, &mut bkt);
+    passes.finished()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::Phase;
+
+    fn reporter() -> Reporter<'static> {
+        Reporter::new(None, None, Phase::SuffixSort, 0)
+    }
+
+    fn sorted<I: SaIndex>(old: &[u8]) -> Vec<I> {
+        build(old, &mut reporter()).unwrap()
+    }
+
+    fn naive(old: &[u8]) -> Vec<u32> {
+        let mut sa: Vec<u32> = (0..=old.len() as u32).collect();
+        sa.sort_by(|&a, &b| old[a as usize..].cmp(&old[b as usize..]));
+        sa
+    }
+
+    #[test]
+    fn test_sais_matches_naive() {
+        let mut seed = 12345u32;
+        let mut random = |modulus: u32| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            ((seed >> 16) % modulus) as u8
+        };
+
+        let mut inputs: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![7],
+            b"banana".to_vec(),
+            b"mississippi".to_vec(),
+            vec![0; 100],
+            vec![255; 33],
+            b"abababababababab".to_vec(),
+        ];
+        for len in [10, 100, 1000, 5000] {
+            inputs.push((0..len).map(|_| random(4)).collect());
+            inputs.push((0..len).map(|_| random(256)).collect());
+        }
+
+        for old in &inputs {
+            assert_eq!(sorted::<u32>(old), naive(old), "input {:?}", old);
+            let wide: Vec<u32> = sorted::<u64>(old).iter().map(|&x| x as u32).collect();
+            assert_eq!(wide, naive(old));
+        }
+    }
+
+    #[test]
+    fn test_content_hash_is_stable() {
+        // Saved indexes depend on these exact values
+        assert_eq!(content_hash(b""), 0xe220_a839_7b1d_cdaf);
+        assert_eq!(content_hash(b"bsdiff-android"), 0x085a_a375_eed4_9b98);
+        assert_ne!(content_hash(b"abc"), content_hash(b"abd"));
+        assert_ne!(content_hash(&[0; 8]), content_hash(&[0; 9]));
+    }
+
+    #[test]
+    fn test_search_longest_match() {
+        let old = b"the quick brown fox jumps over the lazy dog";
+        let sa = SuffixArray::new(old, &mut reporter()).unwrap();
+        let (pos, len) = sa.search(old, b"lazy cat");
+        assert_eq!(&old[pos..pos + len], b"lazy ");
+    }
+}
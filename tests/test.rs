@@ -1,5 +1,5 @@
-use std::io::ErrorKind;
 use bsdiff_android as bsdiff;
+use std::io::ErrorKind;
 
 #[test]
 fn test_it() {